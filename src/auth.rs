@@ -0,0 +1,50 @@
+use russh::keys::ssh_key::AuthorizedKeys;
+use russh::keys::PublicKey;
+use std::path::Path;
+
+/// Load the public keys listed in an OpenSSH `authorized_keys` file
+pub fn load_authorized_keys(path: &Path) -> anyhow::Result<Vec<PublicKey>> {
+    let entries = AuthorizedKeys::read_file(path)?;
+    Ok(entries.into_iter().map(PublicKey::from).collect())
+}
+
+/// Whether `key` is listed; the file is re-read so edits apply to the next login
+pub fn is_authorized(path: &Path, key: &PublicKey) -> anyhow::Result<bool> {
+    let keys = load_authorized_keys(path)?;
+    Ok(keys.iter().any(|k| k.key_data() == key.key_data()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use russh::keys::signature::rand_core::OsRng;
+    use russh::keys::{Algorithm, PrivateKey};
+
+    fn public_key() -> PublicKey {
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap().public_key().clone()
+    }
+
+    #[test]
+    fn only_listed_keys_are_authorized() {
+        let dir = TempDir::new();
+        let path = dir.path().join("authorized_keys");
+        let (listed, other) = (public_key(), public_key());
+        let line = listed.to_openssh().unwrap();
+        std::fs::write(&path, format!("# demo users\nno-pty {} alice@laptop\n\n", line)).unwrap();
+
+        assert!(is_authorized(&path, &listed).unwrap());
+        assert!(!is_authorized(&path, &other).unwrap());
+
+        // Edits apply without a restart
+        std::fs::write(&path, format!("{}\n", other.to_openssh().unwrap())).unwrap();
+        assert!(!is_authorized(&path, &listed).unwrap());
+        assert!(is_authorized(&path, &other).unwrap());
+    }
+
+    #[test]
+    fn unreadable_file_is_an_error() {
+        let dir = TempDir::new();
+        assert!(is_authorized(&dir.path().join("missing"), &public_key()).is_err());
+    }
+}
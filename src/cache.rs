@@ -0,0 +1,83 @@
+use crate::llm::ChatOutcome;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Answers to recent requests, keyed by a hash of the request body, shared by all sessions
+pub struct ResponseCache {
+    capacity: usize,
+    /// Least recently used first
+    entries: Mutex<VecDeque<(u64, ChatOutcome)>>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// The answer stored for `key`, marking it as recently used
+    pub fn get(&self, key: u64) -> Option<ChatOutcome> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let pos = entries.iter().position(|(k, _)| *k == key)?;
+        let entry = entries.remove(pos)?;
+        let outcome = entry.1.clone();
+        entries.push_back(entry);
+        Some(outcome)
+    }
+
+    /// Store an answer, evicting the least recently used one when full
+    pub fn insert(&self, key: u64, outcome: ChatOutcome) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|(k, _)| *k != key);
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((key, outcome));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(content: &str) -> ChatOutcome {
+        ChatOutcome { content: content.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn least_recently_used_answer_is_evicted() {
+        let cache = ResponseCache::new(2);
+        cache.insert(1, answer("one"));
+        cache.insert(2, answer("two"));
+        // Reading 1 makes 2 the oldest
+        assert_eq!(cache.get(1).unwrap().content, "one");
+        cache.insert(3, answer("three"));
+
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(1).unwrap().content, "one");
+        assert_eq!(cache.get(3).unwrap().content, "three");
+    }
+
+    #[test]
+    fn reinserting_a_key_replaces_its_answer() {
+        let cache = ResponseCache::new(2);
+        cache.insert(1, answer("old"));
+        cache.insert(1, answer("new"));
+        cache.insert(2, answer("two"));
+
+        assert_eq!(cache.get(1).unwrap().content, "new");
+        assert_eq!(cache.get(2).unwrap().content, "two");
+    }
+
+    #[test]
+    fn zero_capacity_stores_nothing() {
+        let cache = ResponseCache::new(0);
+        cache.insert(1, answer("one"));
+        assert!(cache.get(1).is_none());
+    }
+}
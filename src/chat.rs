@@ -1,54 +1,368 @@
 use crate::config::Config;
-use crate::llm::{LlmClient, Message};
-use crate::logger::{ClientLogger, UserSummary};
+use crate::i18n::{language_codes, language_name};
+use crate::limiter::RequestQueue;
+use crate::llm::{is_context_length_error, ChatOutcome, GenerationParams, LlmClient, Message, StreamEvent, Usage};
+use crate::logger::{is_valid_snapshot_label, ClientLogger, UserSummary};
+use crate::rewrite::apply_rules;
+use crate::stats::{format_duration, ServerStats};
+use crate::terminal::strip_control_sequences;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Consecutive backend errors after which the user gets a troubleshooting hint
+const ERROR_HINT_THRESHOLD: u32 = 3;
+
+/// Instructions for folding old turns into the rolling summary
+const SUMMARY_PROMPT: &str = "Summarize the conversation below so it can be continued later. \
+Keep names, facts, decisions, preferences and open questions; be brief. Reply with the summary only.";
+
+/// A slash command, as dispatched by `handle_command`, listed in `/help` and tab-completed
+struct Command {
+    name: &'static str,
+    /// Other names that run the same command
+    aliases: &'static [&'static str],
+    /// Argument synopsis shown in `/help`, e.g. `<name>`
+    args: &'static str,
+    description: &'static str,
+    /// Only operators may run it
+    operator: bool,
+    /// Unavailable in kiosk mode, like everything touching stored data
+    kiosk_disabled: bool,
+}
+
+impl Command {
+    const fn new(name: &'static str, args: &'static str, description: &'static str) -> Self {
+        Self { name, aliases: &[], args, description, operator: false, kiosk_disabled: false }
+    }
+    
+    const fn aliases(self, aliases: &'static [&'static str]) -> Self {
+        Self { aliases, ..self }
+    }
+    
+    const fn operator(self) -> Self {
+        Self { operator: true, kiosk_disabled: true, ..self }
+    }
+    
+    const fn kiosk_disabled(self) -> Self {
+        Self { kiosk_disabled: true, ..self }
+    }
+    
+    fn is_available(&self, config: &Config, identity: &str) -> bool {
+        (!self.operator || config.is_admin(identity)) && !(config.kiosk && self.kiosk_disabled)
+    }
+}
+
+/// Every slash command, in `/help` order
+const COMMANDS: &[Command] = &[
+    Command::new("/name", "<name>", "Set your name"),
+    Command::new("/clear", "[all]", "Clear history (all: also today's log)"),
+    Command::new("/regenerate", "", "Get a new answer to your last message").aliases(&["/retry"]),
+    Command::new("/undo", "", "Remove the last exchange"),
+    Command::new("/nocache", "<message>", "Send a message without reusing a cached answer"),
+    Command::new("/stats", "", "Show session stats"),
+    Command::new("/whoami", "", "Show how the server identifies you"),
+    Command::new("/tokens", "", "Show token usage"),
+    Command::new("/context", "", "Show context usage and the summary of older messages"),
+    Command::new("/export", "", "Print the conversation as Markdown"),
+    Command::new("/save", "<label>", "Save the conversation to return to later").kiosk_disabled(),
+    Command::new("/load", "<label>", "Continue a saved conversation").kiosk_disabled(),
+    Command::new("/snapshots", "", "List saved conversations").kiosk_disabled(),
+    Command::new("/search", "<text>", "Find past messages").kiosk_disabled(),
+    Command::new("/multiline", "", "Toggle multi-line input (send with a lone . or Ctrl+D)"),
+    Command::new("/clear-errors", "", "Reset the error count"),
+    Command::new("/reasoning", "on|off", "Show model reasoning"),
+    Command::new("/think", "", "Toggle showing model reasoning"),
+    Command::new("/stream", "on|off", "Stream responses as they arrive"),
+    Command::new("/lang", "[code|off]", "Always respond in this language"),
+    // Kiosk mode pins the model and the response length, which decide what a demo costs;
+    // the other sampling settings only change how this session's answers read
+    Command::new("/model", "[name]", "Show or switch the model").kiosk_disabled(),
+    Command::new("/models", "", "List the backend's models").kiosk_disabled(),
+    Command::new("/temp", "[value]", "Show or set the sampling temperature"),
+    Command::new("/topp", "[value]", "Show or set top-p"),
+    Command::new("/maxtokens", "[n]", "Show or set the response token limit").kiosk_disabled(),
+    Command::new("/seed", "[n]", "Show or set the sampling seed"),
+    Command::new("/system", "[prompt|reset]", "Show or replace the system prompt").kiosk_disabled(),
+    Command::new("/persona", "[name|list]", "Switch to one of the server's personas").kiosk_disabled(),
+    Command::new("/mydata", "", "Show what is stored about you").kiosk_disabled(),
+    Command::new("/deletedata", "", "Delete your stored data").kiosk_disabled(),
+    Command::new("/forgetme", "", "Erase everything stored about you").kiosk_disabled(),
+    Command::new("/help", "", "Show this"),
+    Command::new("/quit", "", "Exit").aliases(&["/exit"]),
+    Command::new("/uptime", "", "Server uptime").operator(),
+    Command::new("/server-stats", "", "Server-wide statistics").operator(),
+];
+
+/// The command called `name` or one of its aliases
+fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name || c.aliases.contains(&name))
+}
+
+/// Command names and aliases available to `identity`, in `/help` order
+pub fn available_commands(config: &Config, identity: &str) -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .filter(|c| c.is_available(config, identity))
+        .flat_map(|c| std::iter::once(c.name).chain(c.aliases.iter().copied()))
+        .collect()
+}
+
+/// `/help` output listing the commands available to `identity`
+fn help_text(config: &Config, identity: &str) -> String {
+    let line = |c: &Command| {
+        let mut line = format!("\n  {}", c.name);
+        if !c.args.is_empty() {
+            line.push_str(&format!(" {}", c.args));
+        }
+        line.push_str(&format!(" - {}", c.description));
+        if !c.aliases.is_empty() {
+            line.push_str(&format!(" (also {})", c.aliases.join(", ")));
+        }
+        line
+    };
+    let available = || COMMANDS.iter().filter(|c| c.is_available(config, identity));
+    let mut help = "Commands:".to_string();
+    help.extend(available().filter(|c| !c.operator).map(line));
+    if available().any(|c| c.operator) {
+        help.push_str("\nOperator commands:");
+        help.extend(available().filter(|c| c.operator).map(line));
+    }
+    help
+}
+
+/// Parse a `/temp`-style argument: `default` clears the override, otherwise the value must be in `range`
+fn parse_setting<T: FromStr + PartialOrd>(arg: &str, range: RangeInclusive<T>) -> Option<Option<T>> {
+    if arg == "default" {
+        return Some(None);
+    }
+    arg.parse().ok().filter(|v| range.contains(v)).map(Some)
+}
+
+/// Display a sampling setting, which the backend chooses when unset
+fn show_setting<T: Display>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_else(|| "backend default".to_string())
+}
+
+/// Shorten a hex key fingerprint to its first and last characters
+fn mask_fingerprint(hex: &str) -> String {
+    match (hex.get(..8), hex.get(hex.len().saturating_sub(6)..)) {
+        (Some(start), Some(end)) if hex.len() > 16 => format!("{}…{}", start, end),
+        _ => hex.to_string(),
+    }
+}
+
+/// One line of `/tokens` output
+fn format_usage(usage: &Usage) -> String {
+    format!(
+        "{} prompt + {} completion = {} tokens",
+        usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+    )
+}
+
+/// Rough token count for a message: about four characters per token plus role overhead
+fn estimate_tokens(message: &Message) -> usize {
+    message.content.chars().count().div_ceil(4) + 4
+}
+
+/// Share of the context window above which `/context` warns
+const CONTEXT_WARN_PERCENT: usize = 90;
+
+/// Most matches `/search` lists
+const SEARCH_RESULTS: usize = 10;
+
+/// Characters of each `/search` match shown before it is cut off
+const SEARCH_SNIPPET_CHARS: usize = 120;
+
+/// Most conversations one user can keep with `/save`
+const MAX_SNAPSHOTS: usize = 50;
+
+/// A conversation stored with `/save`
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    saved: String,
+    messages: Vec<Message>,
+    /// Summary of turns dropped before the snapshot was taken
+    #[serde(default)]
+    context_summary: Option<String>,
+}
+
+/// Drop the last user message and everything after it; false if there was none
+fn remove_last_exchange(messages: &mut Vec<Message>) -> bool {
+    let Some(pos) = messages.iter().rposition(|m| m.role == "user") else {
+        return false;
+    };
+    messages.truncate(pos);
+    true
+}
 
 /// Chat session for a single client
 pub struct ChatSession {
     config: Arc<Config>,
-    llm: LlmClient,
+    stats: Arc<ServerStats>,
+    /// Shared limit on LLM requests in flight
+    llm_queue: Arc<RequestQueue>,
+    llm: Arc<LlmClient>,
+    /// Ask the backend even if an identical request was answered before; set for one turn
+    skip_cache: bool,
     logger: ClientLogger,
+    identity: String,
+    client_ip: String,
+    /// Unique id of this session, also written to every log entry
+    session_id: String,
     messages: Vec<Message>,
     user_summary: UserSummary,
+    error_count: u32,
+    consecutive_errors: u32,
+    show_reasoning: bool,
+    /// Model chosen with `/model`, replacing `config.model` for this session
+    model_override: Option<String>,
+    /// Prompt set with `/system`, replacing `config.system_prompt` for this session
+    system_prompt_override: Option<String>,
+    /// Sampling overrides set with `/temp`, `/topp` and `/maxtokens`
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<u32>,
+    /// Seed set with `/seed`
+    seed: Option<u64>,
+    /// Usage of the last response (`None` if the backend didn't report it)
+    last_usage: Option<Usage>,
+    /// `system_fingerprint` of the last response, to check seeded runs hit the same backend setup
+    last_fingerprint: Option<String>,
+    /// Sum of reported usage this session
+    session_usage: Usage,
+    /// Rolling summary of turns that were dropped from `messages`
+    context_summary: Option<String>,
+    /// Language of the client's forwarded locale, hinted to the model unless it is English
+    locale_language: Option<String>,
+    /// The user logged out on purpose, so the session isn't kept for a reconnect
+    ended: bool,
 }
 
 impl ChatSession {
-    pub fn new(config: Arc<Config>, logger: ClientLogger) -> Self {
-        let llm = LlmClient::new(config.clone());
+    pub fn new(
+        config: Arc<Config>,
+        stats: Arc<ServerStats>,
+        llm_queue: Arc<RequestQueue>,
+        llm: Arc<LlmClient>,
+        logger: ClientLogger,
+        identity: String,
+        client_ip: String,
+    ) -> Self {
+        let session_id = Uuid::new_v4().to_string();
+        let logger = logger.with_session_id(session_id.clone());
         
+        // Kiosk sessions start fresh and leave nothing on disk
+        let (messages, user_summary) = if config.kiosk {
+            (Vec::new(), UserSummary::default())
+        } else {
+            Self::load_user_state(&logger, &config)
+        };
+        
+        Self {
+            config,
+            stats,
+            llm_queue,
+            llm,
+            skip_cache: false,
+            logger,
+            identity,
+            client_ip,
+            session_id,
+            messages,
+            user_summary,
+            error_count: 0,
+            consecutive_errors: 0,
+            show_reasoning: false,
+            model_override: None,
+            system_prompt_override: None,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            seed: None,
+            last_usage: None,
+            last_fingerprint: None,
+            session_usage: Usage::default(),
+            context_summary: None,
+            locale_language: None,
+            ended: false,
+        }
+    }
+    
+    /// Start a logged session and load the user's summary and today's history
+    fn load_user_state(logger: &ClientLogger, config: &Config) -> (Vec<Message>, UserSummary) {
         // Initialize logger and load summary
         let _ = logger.init();
+        match logger.remove_old_logs(config.log_retention_days) {
+            Ok(0) => {}
+            Ok(count) => info!("Removed {} expired chat log(s)", count),
+            Err(e) => warn!("Could not remove expired chat logs: {}", e),
+        }
         let _ = logger.log_session_start();
         let user_summary = logger.update_session_start().unwrap_or_default();
         
         // Load chat history for context
-        let history = logger.load_today_history();
-        let mut messages = Vec::new();
+        let history = logger.load_today_history(config.max_loaded_history);
+        let mut messages: Vec<Message> = Vec::new();
         
         for (role, content) in history {
             let role = match role.as_str() {
                 "user" => "user",
                 "assistant" | "ai" => "assistant",
+                "event" if content == "undo" => {
+                    remove_last_exchange(&mut messages);
+                    continue;
+                }
                 _ => continue,
             };
-            messages.push(Message {
-                role: role.to_string(),
-                content,
-            });
+            // The log is append-only: a failed turn leaves a lone user line and
+            // /regenerate a second answer, so the latest entry of a run wins
+            match messages.last_mut() {
+                Some(last) if last.role == role => *last = Message::new(role, content),
+                _ => messages.push(Message::new(role, content)),
+            }
         }
         
-        Self {
-            config,
-            llm,
-            logger,
-            messages,
-            user_summary,
+        // The history window can start mid-exchange
+        if messages.first().is_some_and(|m| m.role == "assistant") {
+            messages.remove(0);
         }
+        
+        (messages, user_summary)
+    }
+    
+    /// Model used for this session's requests
+    fn model(&self) -> &str {
+        self.model_override.as_deref().unwrap_or(&self.config.model)
+    }
+    
+    /// Model and sampling settings for the next request
+    fn params(&self) -> GenerationParams {
+        GenerationParams {
+            model: self.model().to_string(),
+            temperature: self.temperature.or(self.config.temperature),
+            top_p: self.top_p.or(self.config.top_p),
+            max_tokens: self.max_tokens.or(self.config.max_tokens),
+            seed: self.seed.or(self.config.seed),
+            use_cache: !self.skip_cache,
+        }
+    }
+    
+    /// System prompt before personalization: the `/system` override or the configured one
+    fn base_system_prompt(&self) -> &str {
+        self.system_prompt_override.as_deref().unwrap_or(&self.config.system_prompt)
     }
     
     /// Get personalized system prompt
     fn system_prompt(&self) -> String {
-        let mut prompt = self.config.system_prompt.clone();
+        let mut prompt = self.base_system_prompt().to_string();
         
         if let Some(ref name) = self.user_summary.name {
             prompt.push_str(&format!("\n\nThe user's name is {}. Address them by name occasionally.", name));
@@ -61,102 +375,822 @@ impl ChatSession {
             ));
         }
         
+        if let Some(name) = self.user_summary.language.as_deref().and_then(language_name) {
+            prompt.push_str(&format!("\n\nAlways respond in {}.", name));
+        } else if let Some(ref lang) = self.locale_language
+            && lang != "en"
+        {
+            prompt.push_str(&format!(
+                "\n\nThe user's locale language is `{}`. Respond in the user's locale language unless asked otherwise.",
+                lang
+            ));
+        }
+        
+        if let Some(ref summary) = self.context_summary {
+            prompt.push_str(&format!("\n\nSummary of the earlier conversation:\n{}", summary));
+        }
+        
         prompt
     }
     
+    /// Conversation history sent as context with the next message
+    #[cfg(test)]
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+    
     /// Build messages for LLM including system prompt
-    fn build_messages(&self, user_input: &str) -> Vec<Message> {
-        let mut msgs = vec![Message {
-            role: "system".to_string(),
-            content: self.system_prompt(),
-        }];
+    fn build_messages(&self, user_input: &str) -> Result<Vec<Message>, String> {
+        let mut msgs = vec![Message::new("system", self.system_prompt())];
         
         // Add history
         msgs.extend(self.messages.clone());
         
-        // Add current message
-        msgs.push(Message {
-            role: "user".to_string(),
-            content: user_input.to_string(),
-        });
+        // Add current message, wrapped in the configured template if any
+        let content = match self.config.input_template {
+            Some(ref template) => template.replace("{input}", user_input),
+            None => user_input.to_string(),
+        };
+        msgs.push(Message::new("user", content));
+        
+        // Drop the oldest exchanges until the request fits the configured size
+        if let Some(limit) = self.config.max_request_bytes {
+            let params = self.params();
+            let mut size = self.llm.request_size(&params, &msgs);
+            while size > limit {
+                if msgs.len() <= 2 {
+                    return Err(format!(
+                        "Message too large: request is {} bytes, limit is {} bytes",
+                        size, limit
+                    ));
+                }
+                // A user turn goes together with the answer to it
+                let end = 3.min(msgs.len() - 1);
+                for msg in msgs.drain(1..end) {
+                    size = size.saturating_sub(msg.request_len());
+                }
+            }
+        }
         
-        msgs
+        Ok(msgs)
     }
     
-    /// Process user input and return response
-    pub async fn process_input(&mut self, input: &str) -> Result<String, String> {
+    /// Process user input and return response.
+    ///
+    /// When streaming, the answer is sent to `output` piece by piece and the
+    /// returned string only holds whatever should be shown after it.
+    pub async fn process_input(&mut self, input: &str, output: &UnboundedSender<String>) -> Result<String, String> {
         let input = input.trim();
         
         if input.is_empty() {
             return Ok(String::new());
         }
         
+        // Also covers a cancelled /nocache or /regenerate turn
+        self.skip_cache = false;
+        
         // Handle special commands
         if input.starts_with('/') {
-            return self.handle_command(input);
+            // These need the LLM (and the stream), so they bypass handle_command
+            let (name, arg) = input.split_once(' ').unwrap_or((input, ""));
+            match find_command(&name.to_lowercase()).map(|c| c.name) {
+                Some("/regenerate") => return self.regenerate(output).await,
+                Some("/nocache") => {
+                    let arg = arg.trim();
+                    if arg.is_empty() {
+                        return Ok("Usage: /nocache <message>".to_string());
+                    }
+                    self.skip_cache = true;
+                    let result = self.chat_turn(arg, output, true).await;
+                    self.skip_cache = false;
+                    return result;
+                }
+                _ => {}
+            }
+            return self.handle_command(input).await;
         }
         
+        self.chat_turn(input, output, true).await
+    }
+    
+    /// Drop the last exchange and ask again with the same user message
+    async fn regenerate(&mut self, output: &UnboundedSender<String>) -> Result<String, String> {
+        let Some(pos) = self.messages.iter().rposition(|m| m.role == "user") else {
+            return Ok("Nothing to regenerate yet.".to_string());
+        };
+        let input = self.messages[pos].content.clone();
+        let previous = self.messages.split_off(pos);
+        
+        // The user message is already in the log; a cached answer would just repeat the old one
+        self.skip_cache = true;
+        let result = self.chat_turn(&input, output, false).await;
+        self.skip_cache = false;
+        
+        // Refused before anything was sent (e.g. the daily limit): keep the old exchange
+        if self.messages.len() == pos {
+            self.messages = [std::mem::take(&mut self.messages), previous].concat();
+        }
+        result
+    }
+    
+    /// Count an LLM request towards the user's daily limit, refusing it once the limit is reached
+    fn take_daily_message(&mut self) -> Result<(), String> {
+        let limit = self.config.daily_message_limit;
+        if limit == 0 {
+            return Ok(());
+        }
+        // Kiosk sessions store nothing, so their counts only live in memory
+        let allowed = if self.config.kiosk {
+            self.logger.take_unstored_daily_message(limit)
+        } else {
+            match self.logger.take_daily_message(limit) {
+                Ok((allowed, stored)) => {
+                    self.user_summary.messages_today = stored.messages_today;
+                    self.user_summary.messages_day = stored.messages_day;
+                    allowed
+                }
+                Err(e) => {
+                    warn!("Could not update the daily message count for {}: {}", self.identity, e);
+                    true
+                }
+            }
+        };
+        if !allowed {
+            return Err(format!("Daily limit reached ({} messages), come back tomorrow.", limit));
+        }
+        Ok(())
+    }
+    
+    /// Send one user message to the LLM and record the exchange
+    async fn chat_turn(&mut self, input: &str, output: &UnboundedSender<String>, log_user: bool) -> Result<String, String> {
+        // Build messages for LLM (before adding to history, so the input isn't sent twice)
+        let llm_messages = self.build_messages(input)?;
+        
+        // Every request counts, logged or not (kiosk mode, /regenerate)
+        self.take_daily_message()?;
+        
         // Log user message
-        let _ = self.logger.log_message("user", input);
+        if log_user && !self.config.kiosk {
+            let _ = self.logger.log_message("user", input);
+            self.user_summary.total_messages += 1;
+        }
+        self.stats.message_processed();
+        
+        // Get response from LLM
+        let live = self.streams_live();
+        let mut result = self.request(llm_messages, output).await;
+        let mut notice = None;
+        
+        // On a context-length error, drop the older half of the history and retry once;
+        // an even count keeps user/assistant pairs together
+        if let Err(ref e) = result
+            && is_context_length_error(e)
+            && self.config.retry_on_context_error
+            && !self.messages.is_empty()
+        {
+            let dropped = (self.messages.len() / 4 * 2).max(2).min(self.messages.len());
+            self.messages.drain(..dropped);
+            let text = format!(
+                "(Conversation too long for the model - dropped {} older messages and retried.)\n\n",
+                dropped
+            );
+            if live {
+                let _ = output.send(text);
+            } else {
+                notice = Some(text);
+            }
+            result = self.request(self.build_messages(input)?, output).await;
+        }
         
         // Add to history
-        self.messages.push(Message {
-            role: "user".to_string(),
-            content: input.to_string(),
-        });
+        self.messages.push(Message::new("user", input));
         
-        // Build messages for LLM
-        let llm_messages = self.build_messages(input);
+        let outcome = match result {
+            Ok(outcome) => {
+                self.consecutive_errors = 0;
+                self.last_usage = outcome.usage;
+                self.last_fingerprint = outcome.system_fingerprint.clone();
+                if let Some(usage) = outcome.usage {
+                    self.session_usage += usage;
+                }
+                outcome
+            }
+            Err(e) if is_context_length_error(&e) => {
+                return Err(self.record_error(
+                    "Conversation too long for the model's context window. Use /clear to start fresh.".to_string(),
+                ));
+            }
+            Err(e) => return Err(self.record_error(e)),
+        };
         
-        // Remove duplicate (it's in llm_messages)
-        self.messages.pop();
-        self.messages.push(Message {
-            role: "user".to_string(),
-            content: input.to_string(),
-        });
+        // Never let the model drive the user's terminal
+        let (response, reasoning) = if self.config.allow_model_ansi {
+            (outcome.content, outcome.reasoning)
+        } else {
+            (
+                strip_control_sequences(&outcome.content),
+                outcome.reasoning.map(|r| strip_control_sequences(&r)),
+            )
+        };
         
-        // Get response from LLM
-        let response = self.llm.chat(llm_messages).await?;
+        // Operator-configured rewrites apply to both display and logs
+        let response = apply_rules(&self.config.rewrite_rules, &response);
+        let reasoning = reasoning.map(|r| apply_rules(&self.config.rewrite_rules, &r));
         
         // Log and store assistant response
-        let _ = self.logger.log_message("assistant", &response);
-        self.messages.push(Message {
-            role: "assistant".to_string(),
-            content: response.clone(),
-        });
+        if !self.config.kiosk {
+            let _ = self.logger.log_message("assistant", &response);
+        }
+        self.messages.push(Message::new("assistant", response.clone()));
         
         // Keep message history manageable
-        while self.messages.len() > 40 {
-            self.messages.remove(0);
+        self.compact_history().await;
+        
+        // Everything was already shown as it arrived
+        if live {
+            return Ok(String::new());
         }
         
-        Ok(response)
+        let mut display = notice.unwrap_or_default();
+        if self.show_reasoning && let Some(reasoning) = reasoning {
+            display.push_str(&format!("\x1b[2m[reasoning]\n{}\x1b[0m\n\n", reasoning.trim()));
+        }
+        display.push_str(&response);
+        Ok(display)
+    }
+    
+    /// The conversation as Markdown, for copying out of the terminal
+    fn export_markdown(&self) -> String {
+        let mut out = format!(
+            "# sshllm conversation\n\n- Date: {}\n- Model: {}\n",
+            Local::now().format("%Y-%m-%d"),
+            self.model()
+        );
+        if let Some(ref summary) = self.context_summary {
+            out.push_str(&format!("\n> Earlier messages, summarized: {}\n", summary.replace('\n', "\n> ")));
+        }
+        for message in &self.messages {
+            let label = if message.role == "user" { "**You:**" } else { "**AI:**" };
+            out.push_str(&format!("\n{}\n\n{}\n", label, message.content.trim_end()));
+        }
+        out
+    }
+    
+    /// Estimated tokens the system prompt and history take up in the next request
+    fn context_tokens(&self) -> usize {
+        estimate_tokens(&Message::new("system", self.system_prompt()))
+            + self.messages.iter().map(estimate_tokens).sum::<usize>()
+    }
+    
+    /// Fold the older half of the history into the rolling summary once it passes the
+    /// threshold, then enforce the hard cap on kept messages
+    async fn compact_history(&mut self) {
+        let threshold = self.config.summarize_after;
+        if threshold > 0 && self.messages.len() > threshold {
+            // An even count keeps user/assistant pairs together
+            let count = self.messages.len() / 4 * 2;
+            match self.summarize(count).await {
+                Ok(summary) => {
+                    self.context_summary = Some(summary);
+                    self.messages.drain(..count);
+                }
+                Err(e) => warn!("Could not summarize history for {}, dropping old messages: {}", self.identity, e),
+            }
+        }
+        
+        // Rounded down to even so the oldest kept message is a user turn
+        let cap = self.config.max_history_messages / 2 * 2;
+        if self.messages.len() > cap {
+            self.messages.drain(..self.messages.len() - cap);
+        }
+    }
+    
+    /// Ask the LLM to summarize the previous summary plus the oldest `count` messages
+    async fn summarize(&mut self, count: usize) -> Result<String, String> {
+        let mut transcript = String::new();
+        if let Some(ref summary) = self.context_summary {
+            transcript.push_str(&format!("Earlier summary:\n{}\n\n", summary));
+        }
+        for message in &self.messages[..count] {
+            transcript.push_str(&format!("{}: {}\n\n", message.role, message.content));
+        }
+        
+        let messages = vec![Message::new("system", SUMMARY_PROMPT), Message::new("user", transcript)];
+        let _slot = self.llm_queue.acquire(|_| {}).await;
+        let outcome = self.llm.chat(&self.params(), messages).await?;
+        if let Some(usage) = outcome.usage {
+            self.session_usage += usage;
+        }
+        
+        let summary = strip_control_sequences(outcome.content.trim());
+        if summary.is_empty() {
+            return Err("empty summary".to_string());
+        }
+        Ok(summary)
+    }
+    
+    /// Whether responses should be streamed for this user
+    pub fn streaming(&self) -> bool {
+        self.user_summary.stream.unwrap_or(self.config.stream)
+    }
+    
+    /// Whether the answer is shown live; rewrite rules need the whole answer first
+    fn streams_live(&self) -> bool {
+        self.streaming() && self.config.rewrite_rules.is_empty()
+    }
+    
+    /// Ask the LLM once a request slot is free, recording how long the request took
+    async fn request(&self, messages: Vec<Message>, output: &UnboundedSender<String>) -> Result<ChatOutcome, String> {
+        let _slot = self
+            .llm_queue
+            .acquire(|position| {
+                let _ = output.send(format!("\x1b[2m(waiting in queue, #{})\x1b[0m\n\n", position));
+            })
+            .await;
+        let started = Instant::now();
+        let result = self.send_request(messages, output).await;
+        self.stats.llm_request_finished(started.elapsed());
+        result
+    }
+    
+    /// Send the request, streaming display text to `output` when live streaming is on
+    async fn send_request(&self, messages: Vec<Message>, output: &UnboundedSender<String>) -> Result<ChatOutcome, String> {
+        if !self.streams_live() {
+            return self.llm.chat(&self.params(), messages).await;
+        }
+        
+        let params = self.params();
+        let show_reasoning = self.show_reasoning;
+        let sanitize = !self.config.allow_model_ansi;
+        let mut in_reasoning = false;
+        
+        self.llm
+            .chat_stream(&params, messages, |event| {
+                let (text, is_reasoning) = match event {
+                    StreamEvent::Reasoning(text) => (text, true),
+                    StreamEvent::Content(text) => (text, false),
+                };
+                if is_reasoning && !show_reasoning {
+                    return;
+                }
+                if is_reasoning != in_reasoning {
+                    in_reasoning = is_reasoning;
+                    let _ = output.send(if is_reasoning { "\x1b[2m[reasoning]\n" } else { "\x1b[0m\n\n" }.to_string());
+                }
+                let _ = output.send(if sanitize { strip_control_sequences(&text) } else { text });
+            })
+            .await
+    }
+    
+    /// Count a backend error, adding a hint once failures keep repeating
+    fn record_error(&mut self, error: String) -> String {
+        self.stats.backend_error();
+        self.error_count += 1;
+        self.consecutive_errors += 1;
+        
+        if self.consecutive_errors >= ERROR_HINT_THRESHOLD {
+            format!(
+                "{}\n({} errors in a row - the backend may be down. Try again in a moment or reconnect.)",
+                error, self.consecutive_errors
+            )
+        } else {
+            error
+        }
     }
     
     /// Handle slash commands
-    fn handle_command(&mut self, input: &str) -> Result<String, String> {
+    async fn handle_command(&mut self, input: &str) -> Result<String, String> {
         let parts: Vec<&str> = input.splitn(2, ' ').collect();
         let cmd = parts[0].to_lowercase();
         let arg = parts.get(1).map(|s| s.trim()).unwrap_or("");
         
-        match cmd.as_str() {
+        let Some(command) = find_command(&cmd) else {
+            return Ok("Unknown command. Type /help for available commands.".to_string());
+        };
+        if self.config.kiosk && command.kiosk_disabled {
+            return Ok("This command is disabled on this server.".to_string());
+        }
+        if command.operator && !self.config.is_admin(&self.identity) {
+            return Ok("This command is restricted to operators.".to_string());
+        }
+        
+        match command.name {
             "/name" => {
                 if arg.is_empty() {
                     Ok("Usage: /name <your name>".to_string())
                 } else {
                     self.user_summary.name = Some(arg.to_string());
-                    let _ = self.logger.set_user_name(arg);
+                    if !self.config.kiosk {
+                        let _ = self.logger.set_user_name(arg);
+                    }
                     Ok(format!("Nice to meet you, {}!", arg))
                 }
             }
-            "/clear" => {
+            "/clear" => match arg {
+                "" => {
+                    self.messages.clear();
+                    self.context_summary = None;
+                    Ok("Chat history cleared.".to_string())
+                }
+                "all" => Ok(
+                    "This also deletes today's chat log, so it won't come back when you reconnect.\n  /clear all yes - clear history and today's log"
+                        .to_string(),
+                ),
+                "all yes" => {
+                    if !self.config.kiosk
+                        && let Err(e) = self.logger.clear_today()
+                    {
+                        return Ok(format!("Could not delete today's log: {}", e));
+                    }
+                    self.messages.clear();
+                    self.context_summary = None;
+                    Ok("Chat history and today's log cleared.".to_string())
+                }
+                _ => Ok("Usage: /clear [all]".to_string()),
+            },
+            "/undo" => {
+                if !remove_last_exchange(&mut self.messages) {
+                    return Ok("Nothing to undo.".to_string());
+                }
+                // Logged so reconnecting today doesn't bring the exchange back
+                if !self.config.kiosk {
+                    let _ = self.logger.log_event("undo");
+                }
+                Ok("Removed last exchange.".to_string())
+            }
+            "/stats" => {
+                let summary = &self.user_summary;
+                let since = summary
+                    .first_seen
+                    .map(|t| format!(" since {}", t.with_timezone(&Local).format("%Y-%m-%d")))
+                    .unwrap_or_default();
+                let mut stats = format!(
+                    "You've had {} session(s){}, {} message(s) total.",
+                    summary.total_sessions, since, summary.total_messages
+                );
+                if let Some(last_seen) = summary.last_seen {
+                    stats.push_str(&format!("\n  Last visit: {}", last_seen.with_timezone(&Local).format("%Y-%m-%d %H:%M")));
+                }
+                stats.push_str(&format!(
+                    "\n  Backend errors this session: {} ({} in a row)",
+                    self.error_count, self.consecutive_errors
+                ));
+                Ok(stats)
+            }
+            "/whoami" => {
+                let identity = match self.identity.strip_prefix("key_") {
+                    Some(hex) => format!("SSH key {}", mask_fingerprint(hex)),
+                    None => "guest via IP".to_string(),
+                };
+                Ok(format!(
+                    "Identity: {}\nIP address: {}\nSession: {}\nModel: {}",
+                    identity,
+                    self.client_ip,
+                    self.session_id,
+                    self.model()
+                ))
+            }
+            // Interactive sessions toggle this in the terminal before it gets here
+            "/multiline" => Ok("Multi-line input is only available in interactive sessions.".to_string()),
+            "/search" => {
+                if arg.is_empty() {
+                    return Ok("Usage: /search <text>".to_string());
+                }
+                let hits = self.logger.search(arg, SEARCH_RESULTS);
+                if hits.is_empty() {
+                    return Ok(format!("No messages found containing \"{}\".", arg));
+                }
+                let mut result = format!("Most recent messages containing \"{}\":", arg);
+                for hit in hits {
+                    let who = if hit.role == "user" { "You" } else { "AI" };
+                    let line = hit.content.split_whitespace().collect::<Vec<_>>().join(" ");
+                    let snippet = match line.char_indices().nth(SEARCH_SNIPPET_CHARS) {
+                        Some((end, _)) => format!("{}...", &line[..end]),
+                        None => line,
+                    };
+                    result.push_str(&format!("\n  [{}] {}: {}", hit.time, who, snippet));
+                }
+                Ok(result)
+            }
+            "/save" | "/load" if !is_valid_snapshot_label(arg) => Ok(format!(
+                "Usage: {} <label> (up to 64 letters, digits, - or _)",
+                command.name
+            )),
+            "/save" => {
+                if self.messages.is_empty() {
+                    return Ok("Nothing to save yet.".to_string());
+                }
+                let labels = self.logger.list_snapshots();
+                if labels.len() >= MAX_SNAPSHOTS && !labels.iter().any(|l| l == arg) {
+                    return Ok(format!("You already have {} snapshots; reuse a label to replace one.", MAX_SNAPSHOTS));
+                }
+                let snapshot = Snapshot {
+                    saved: Local::now().format("%Y-%m-%d %H:%M").to_string(),
+                    messages: self.messages.clone(),
+                    context_summary: self.context_summary.clone(),
+                };
+                let data = serde_json::to_string(&snapshot).map_err(|e| e.to_string())?;
+                match self.logger.save_snapshot(arg, &data) {
+                    Ok(()) => Ok(format!("Saved {} messages as '{}'. /load {} returns to them.", snapshot.messages.len(), arg, arg)),
+                    Err(e) => Ok(format!("Could not save the conversation: {}", e)),
+                }
+            }
+            "/load" => {
+                let data = match self.logger.load_snapshot(arg) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => return Ok(format!("No snapshot called '{}'. /snapshots lists them.", arg)),
+                    Err(e) => return Ok(format!("Could not load the snapshot: {}", e)),
+                };
+                let snapshot: Snapshot = match serde_json::from_str(&data) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => return Ok(format!("Snapshot '{}' is unreadable: {}", arg, e)),
+                };
+                self.messages = snapshot.messages;
+                self.context_summary = snapshot.context_summary;
+                Ok(format!(
+                    "Loaded '{}' ({} messages, saved {}). It replaces the conversation for this session.",
+                    arg,
+                    self.messages.len(),
+                    snapshot.saved
+                ))
+            }
+            "/snapshots" => {
+                let labels = self.logger.list_snapshots();
+                if labels.is_empty() {
+                    return Ok("No saved conversations. /save <label> saves this one.".to_string());
+                }
+                Ok(format!("Saved conversations: {}\nUse /load <label> to continue one.", labels.join(", ")))
+            }
+            "/export" => {
+                if self.messages.is_empty() {
+                    return Ok("Nothing to export yet.".to_string());
+                }
+                Ok(self.export_markdown())
+            }
+            "/context" => {
+                let summary = match self.context_summary {
+                    Some(ref summary) => format!("Summary of earlier messages:\n{}", summary),
+                    None => "Nothing has been summarized yet.".to_string(),
+                };
+                let used = self.context_tokens();
+                let size = self.config.context_size;
+                let percent = used * 100 / size.max(1);
+                let mut usage = format!("Using ~{} / {} tokens ({}%).", used, size, percent);
+                if percent > CONTEXT_WARN_PERCENT {
+                    usage = format!("\x1b[1;31m{} Use /clear to start fresh.\x1b[0m", usage);
+                }
+                Ok(format!(
+                    "{}\n{} messages in the conversation window.\n{}",
+                    usage,
+                    self.messages.len(),
+                    summary
+                ))
+            }
+            "/tokens" => {
+                let last = match self.last_usage {
+                    Some(ref usage) => format_usage(usage),
+                    None => "not reported by backend".to_string(),
+                };
+                let mut text = format!(
+                    "Token usage:\n  Last response: {}\n  This session: {}",
+                    last,
+                    format_usage(&self.session_usage)
+                );
+                if let Some(ref fingerprint) = self.last_fingerprint {
+                    text.push_str(&format!("\n  System fingerprint: {}", fingerprint));
+                }
+                Ok(text)
+            }
+            "/clear-errors" => {
+                self.error_count = 0;
+                self.consecutive_errors = 0;
+                Ok("Error count reset.".to_string())
+            }
+            "/reasoning" | "/think" => {
+                // A bare /think flips the setting
+                let arg = match arg {
+                    "" if cmd == "/think" => if self.show_reasoning { "off" } else { "on" },
+                    arg => arg,
+                };
+                match arg {
+                    "on" => {
+                        self.show_reasoning = true;
+                        Ok("Model reasoning will be shown (when the backend provides it).".to_string())
+                    }
+                    "off" => {
+                        self.show_reasoning = false;
+                        Ok("Model reasoning hidden.".to_string())
+                    }
+                    _ => Ok(format!(
+                        "Reasoning display is {}. Usage: /reasoning on|off",
+                        if self.show_reasoning { "on" } else { "off" }
+                    )),
+                }
+            }
+            "/stream" => {
+                let enabled = match arg {
+                    "on" => true,
+                    "off" => false,
+                    _ => {
+                        return Ok(format!(
+                            "Streaming is {}. Usage: /stream on|off",
+                            if self.streaming() { "on" } else { "off" }
+                        ));
+                    }
+                };
+                self.user_summary.stream = Some(enabled);
+                if !self.config.kiosk {
+                    let _ = self.logger.update_summary(|s| s.stream = Some(enabled));
+                }
+                Ok(format!("Streaming {}.", if enabled { "on" } else { "off" }))
+            }
+            "/lang" => {
+                let language = match arg {
+                    "" => {
+                        return Ok(match self.user_summary.language.as_deref().and_then(language_name) {
+                            Some(name) => format!("Responses are always in {}. /lang off lets the model choose.", name),
+                            None => format!("No language set. Usage: /lang <{}>|off", language_codes().join("|")),
+                        });
+                    }
+                    "off" => None,
+                    code => {
+                        let code = code.to_lowercase();
+                        if language_name(&code).is_none() {
+                            return Ok(format!("Unknown language '{}'. Known codes: {}", code, language_codes().join(", ")));
+                        }
+                        Some(code)
+                    }
+                };
+                self.user_summary.language = language.clone();
+                if !self.config.kiosk {
+                    let _ = self.logger.update_summary(|s| s.language = language.clone());
+                }
+                Ok(match self.user_summary.language.as_deref().and_then(language_name) {
+                    Some(name) => format!("Responses will be in {}.", name),
+                    None => "Language cleared.".to_string(),
+                })
+            }
+            "/models" | "/model" if cmd == "/models" || arg == "list" => {
+                match self.llm.list_models().await {
+                    Ok(models) if models.is_empty() => Ok("The backend reported no models.".to_string()),
+                    Ok(models) => {
+                        let current = self.model();
+                        let lines: Vec<String> = models
+                            .iter()
+                            .map(|m| if m == current { format!("  {} (current)", m) } else { format!("  {}", m) })
+                            .collect();
+                        Ok(format!("Available models:\n{}", lines.join("\n")))
+                    }
+                    Err(e) => Ok(format!("Could not list models: {}", e)),
+                }
+            }
+            "/model" => {
+                if arg.is_empty() {
+                    return Ok(format!("Current model: {}. Usage: /model <name>", self.model()));
+                }
+                self.model_override = (arg != self.config.model).then(|| arg.to_string());
+                Ok(format!("Model set to {}.", arg))
+            }
+            "/system" => match arg {
+                "" => Ok(format!(
+                    "System prompt{}:\n{}\nUsage: /system <prompt>|reset",
+                    if self.system_prompt_override.is_some() { " (set for this session)" } else { "" },
+                    self.base_system_prompt()
+                )),
+                "reset" => {
+                    self.system_prompt_override = None;
+                    Ok("System prompt reset to the server default.".to_string())
+                }
+                prompt => {
+                    self.system_prompt_override = Some(prompt.to_string());
+                    Ok("System prompt set for this session.".to_string())
+                }
+            },
+            "/persona" => {
+                if self.config.personas.is_empty() {
+                    return Ok("No personas are set up on this server.".to_string());
+                }
+                if arg.is_empty() || arg == "list" {
+                    let names: Vec<&str> = self.config.personas.keys().map(String::as_str).collect();
+                    return Ok(format!("Personas: {}\nUsage: /persona <name> (/system reset goes back to the default)", names.join(", ")));
+                }
+                let name = arg.to_lowercase();
+                let Some(prompt) = self.config.personas.get(&name) else {
+                    return Ok(format!("No persona named {}. Try /persona list.", arg));
+                };
+                self.system_prompt_override = Some(prompt.clone());
+                Ok(format!("Persona {} loaded.", name))
+            }
+            "/temp" => {
+                if arg.is_empty() {
+                    return Ok(format!("Temperature: {}. Usage: /temp <0.0-2.0>|default", show_setting(self.params().temperature)));
+                }
+                let Some(value) = parse_setting(arg, 0.0..=2.0) else {
+                    return Ok("Temperature must be a number from 0.0 to 2.0.".to_string());
+                };
+                self.temperature = value;
+                Ok(format!("Temperature set to {}.", show_setting(self.params().temperature)))
+            }
+            "/topp" => {
+                if arg.is_empty() {
+                    return Ok(format!("Top-p: {}. Usage: /topp <0.0-1.0>|default", show_setting(self.params().top_p)));
+                }
+                let Some(value) = parse_setting(arg, 0.0..=1.0) else {
+                    return Ok("Top-p must be a number from 0.0 to 1.0.".to_string());
+                };
+                self.top_p = value;
+                Ok(format!("Top-p set to {}.", show_setting(self.params().top_p)))
+            }
+            "/maxtokens" => {
+                if arg.is_empty() {
+                    return Ok(format!("Max tokens: {}. Usage: /maxtokens <n>|default", show_setting(self.params().max_tokens)));
+                }
+                let Some(value) = parse_setting(arg, 1..=u32::MAX) else {
+                    return Ok("Max tokens must be a positive whole number.".to_string());
+                };
+                self.max_tokens = value;
+                Ok(format!("Max tokens set to {}.", show_setting(self.params().max_tokens)))
+            }
+            "/seed" => {
+                if arg.is_empty() {
+                    return Ok(format!("Seed: {}. Usage: /seed <n>|default", show_setting(self.params().seed)));
+                }
+                let Some(value) = parse_setting(arg, 0..=u64::MAX) else {
+                    return Ok("Seed must be a non-negative whole number.".to_string());
+                };
+                self.seed = value;
+                Ok(format!("Seed set to {}.", show_setting(self.params().seed)))
+            }
+            "/mydata" => {
+                let summary = match self.logger.read_summary() {
+                    Ok(Some(contents)) => contents.trim_end().to_string(),
+                    Ok(None) => "(nothing stored)".to_string(),
+                    Err(e) => return Ok(format!("Could not read your data: {}", e)),
+                };
+                let logs = self.logger.list_chat_logs();
+                let snapshots = self.logger.list_snapshots();
+                Ok(format!(
+                    "Stored summary:\n{}\n\nChat logs: {}\nSnapshots: {}\n\nCopy the text above to keep a copy. Use /deletedata to erase it.",
+                    summary,
+                    if logs.is_empty() { "none".to_string() } else { logs.join(", ") },
+                    if snapshots.is_empty() { "none".to_string() } else { snapshots.join(", ") }
+                ))
+            }
+            "/deletedata" => {
+                match arg {
+                    "yes" | "all yes" => {
+                        let daily = self.stored_daily_count();
+                        if let Err(e) = self.logger.delete_summary() {
+                            return Ok(format!("Could not delete your data: {}", e));
+                        }
+                        self.reset_user_summary(daily);
+                        if arg == "all yes" {
+                            match self.logger.delete_chat_logs().and_then(|logs| Ok((logs, self.logger.delete_snapshots()?))) {
+                                Ok((logs, snapshots)) => {
+                                    self.messages.clear();
+                                    Ok(format!("Deleted your summary, {} chat log(s) and {} snapshot(s).", logs, snapshots))
+                                }
+                                Err(e) => Ok(format!("Deleted your summary, but could not delete logs: {}", e)),
+                            }
+                        } else {
+                            Ok("Deleted your stored summary.".to_string())
+                        }
+                    }
+                    _ => Ok("This erases your stored summary (name, sessions).\n  /deletedata yes - delete the summary\n  /deletedata all yes - also delete your chat logs and snapshots".to_string()),
+                }
+            }
+            "/forgetme" => {
+                if arg != "yes" {
+                    return Ok("This permanently deletes everything stored about you: your summary, all chat logs and snapshots.\n  /forgetme yes - erase it all".to_string());
+                }
+                let daily = self.stored_daily_count();
+                if let Err(e) = self.logger.purge() {
+                    warn!("Could not purge data for {}: {}", self.identity, e);
+                    return Ok(format!("Could not delete your data: {}", e));
+                }
+                info!("Purged stored data for {}", self.identity);
+                let _ = self.logger.init();
                 self.messages.clear();
-                Ok("Chat history cleared.".to_string())
+                self.context_summary = None;
+                self.reset_user_summary(daily);
+                Ok("Everything stored about you has been deleted. Messages you send from now on start a new log.".to_string())
+            }
+            "/help" => Ok(help_text(&self.config, &self.identity)),
+            "/uptime" => {
+                Ok(format!("Server uptime: {}", format_duration(self.stats.uptime_secs())))
             }
-            "/help" => {
-                Ok("Commands:\n  /name <name> - Set your name\n  /clear - Clear history\n  /help - Show this\n  /quit - Exit".to_string())
+            "/server-stats" => {
+                Ok(format!(
+                    "Server stats:\n  Uptime: {}\n  Active sessions: {}\n  Total sessions: {}\n  Messages: {}\n  Backend errors: {}",
+                    format_duration(self.stats.uptime_secs()),
+                    self.stats.active_sessions(),
+                    self.stats.total_sessions(),
+                    self.stats.messages(),
+                    self.stats.backend_errors(),
+                ))
             }
-            "/quit" | "/exit" => {
+            "/quit" => {
+                self.ended = true;
                 Err("quit".to_string())
             }
             _ => {
@@ -165,12 +1199,330 @@ impl ChatSession {
         }
     }
     
-    /// Get welcome message
-    pub fn welcome_message(&self) -> String {
+    /// Today's stored message count, read before the user's data is deleted
+    fn stored_daily_count(&self) -> UserSummary {
+        let stored = self.logger.stored_summary().unwrap_or_default();
+        UserSummary { messages_today: stored.messages_today, messages_day: stored.messages_day, ..Default::default() }
+    }
+    
+    /// Forget the user's summary after their data was deleted. Today's message count (`daily`)
+    /// is kept, and stored again, so deleting data doesn't lift the daily limit
+    fn reset_user_summary(&mut self, daily: UserSummary) {
+        let mut summary = UserSummary::default();
+        if self.config.daily_message_limit > 0 && daily.messages_sent_today() > 0 {
+            summary.messages_today = daily.messages_today;
+            summary.messages_day = daily.messages_day;
+            let _ = self.logger.update_summary(|stored| {
+                stored.messages_today = summary.messages_today;
+                stored.messages_day = summary.messages_day;
+            });
+        }
+        self.user_summary = summary;
+    }
+    
+    /// Set the language taken from the locale the client forwarded
+    pub fn set_locale_language(&mut self, language: Option<String>) {
+        self.locale_language = language;
+    }
+    
+    /// Mark the session as finished by the user (e.g. Ctrl+D)
+    pub fn end(&mut self) {
+        self.ended = true;
+    }
+    
+    /// Whether the user quit rather than lost the connection
+    pub fn has_ended(&self) -> bool {
+        self.ended
+    }
+    
+    /// Record in the chat log that the server closed this session
+    pub fn log_session_end(&self) {
+        if self.config.kiosk {
+            return;
+        }
+        if let Err(e) = self.logger.log_session_end() {
+            warn!("Could not log the session end for {}: {}", self.identity, e);
+        }
+    }
+    
+    /// Record the address of the connection that reattached to this session
+    pub fn set_client_ip(&mut self, client_ip: String) {
+        self.client_ip = client_ip;
+    }
+    
+    /// Greeting for a reconnect that picked this session up again
+    pub fn reattach_message(&self) -> String {
+        match self.messages.len() {
+            0 => "Reconnected to your session.".to_string(),
+            count => format!("Reconnected: continuing your conversation ({} messages).", count),
+        }
+    }
+    
+    /// Get welcome message, localized when a translation exists for `language`
+    pub fn welcome_message(&self, language: Option<&str>) -> String {
+        let translation = self.config.translation(language);
+        
         if let Some(ref name) = self.user_summary.name {
-            format!("Welcome back, {}! How can I help you today?", name)
+            match translation.and_then(|t| t.welcome_back.as_ref()) {
+                Some(text) => text.replace("{name}", name),
+                None => format!("Welcome back, {}! How can I help you today?", name),
+            }
         } else {
-            "Welcome! Type /name <your name> to introduce yourself, or just start chatting!".to_string()
+            match translation.and_then(|t| t.welcome.as_ref()) {
+                Some(text) => text.clone(),
+                None => "Welcome! Type /name <your name> to introduce yourself, or just start chatting!".to_string(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::LogStore;
+    use crate::test_support::{config, json_response, openai_answer, MockBackend, TempDir};
+    
+    fn session(config: &Arc<Config>, store: &LogStore, identity: &str) -> ChatSession {
+        let logger = ClientLogger::new(&config.logs_dir, identity, store);
+        ChatSession::new(
+            config.clone(),
+            Arc::new(ServerStats::new()),
+            Arc::new(RequestQueue::new(0)),
+            Arc::new(LlmClient::new(config.clone(), None).unwrap()),
+            logger,
+            identity.to_string(),
+            "127.0.0.1".to_string(),
+        )
+    }
+    
+    async fn send(session: &mut ChatSession, input: &str) -> Result<String, String> {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        session.process_input(input, &tx).await
+    }
+    
+    /// Config talking to `backend`, logging to `dir`, allowing `limit` messages a day
+    fn limited_config(backend: &MockBackend, dir: &TempDir, limit: u32) -> Config {
+        Config { logs_dir: dir.path().to_path_buf(), daily_message_limit: limit, ..config(&backend.url) }
+    }
+    
+    #[tokio::test]
+    async fn daily_limit_refuses_the_message_after_the_limit() {
+        let backend = MockBackend::start(vec![openai_answer("ok")]).await;
+        let dir = TempDir::new();
+        let config = Arc::new(limited_config(&backend, &dir, 2));
+        let store = LogStore::default();
+        let mut chat = session(&config, &store, "key_a");
+        
+        assert_eq!(send(&mut chat, "one").await.unwrap(), "ok");
+        assert_eq!(send(&mut chat, "two").await.unwrap(), "ok");
+        let refused = send(&mut chat, "three").await.unwrap_err();
+        assert!(refused.starts_with("Daily limit reached"), "{}", refused);
+        assert_eq!(backend.requests(), 2);
+        
+        // Reconnecting doesn't reset the count
+        let mut chat = session(&config, &store, "key_a");
+        assert!(send(&mut chat, "four").await.is_err());
+        assert_eq!(backend.requests(), 2);
+    }
+    
+    #[tokio::test]
+    async fn regenerate_counts_towards_the_daily_limit() {
+        let backend = MockBackend::start(vec![openai_answer("ok")]).await;
+        let dir = TempDir::new();
+        let config = Arc::new(limited_config(&backend, &dir, 2));
+        let store = LogStore::default();
+        let mut chat = session(&config, &store, "key_a");
+        
+        send(&mut chat, "one").await.unwrap();
+        send(&mut chat, "/regenerate").await.unwrap();
+        assert!(send(&mut chat, "/regenerate").await.unwrap_err().starts_with("Daily limit reached"));
+        assert!(send(&mut chat, "two").await.is_err());
+        assert_eq!(backend.requests(), 2);
+        // The refused regeneration keeps the exchange it would have replaced
+        assert_eq!(chat.messages.len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn sessions_of_one_identity_share_the_daily_limit() {
+        let backend = MockBackend::start(vec![openai_answer("ok")]).await;
+        let dir = TempDir::new();
+        let config = Arc::new(limited_config(&backend, &dir, 2));
+        let store = LogStore::default();
+        let mut first = session(&config, &store, "key_a");
+        let mut second = session(&config, &store, "key_a");
+        
+        send(&mut first, "one").await.unwrap();
+        send(&mut second, "two").await.unwrap();
+        assert!(send(&mut first, "three").await.is_err());
+        assert!(send(&mut second, "three").await.is_err());
+        assert_eq!(backend.requests(), 2);
+    }
+    
+    #[tokio::test]
+    async fn kiosk_sessions_are_limited_without_storing_anything() {
+        let backend = MockBackend::start(vec![openai_answer("ok")]).await;
+        let dir = TempDir::new();
+        let config = Arc::new(Config { kiosk: true, ..limited_config(&backend, &dir, 1) });
+        let store = LogStore::default();
+        
+        send(&mut session(&config, &store, "10.0.0.1"), "one").await.unwrap();
+        assert!(send(&mut session(&config, &store, "10.0.0.1"), "two").await.is_err());
+        assert!(send(&mut session(&config, &store, "10.0.0.2"), "two").await.is_ok());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+    
+    fn roles_and_contents(messages: &[Message]) -> Vec<(&str, &str)> {
+        messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect()
+    }
+    
+    #[tokio::test]
+    async fn reconnects_rebuild_history_without_duplicates() {
+        let backend = MockBackend::start(vec![json_response("500 Internal Server Error", "{}"), openai_answer("ok")]).await;
+        let dir = TempDir::new();
+        let config = Arc::new(Config { logs_dir: dir.path().to_path_buf(), ..config(&backend.url) });
+        let store = LogStore::default();
+        
+        let mut chat = session(&config, &store, "key_a");
+        assert!(send(&mut chat, "lost").await.is_err());
+        send(&mut chat, "one").await.unwrap();
+        
+        // The failed turn's lone user line gives way to the one after it
+        let mut chat = session(&config, &store, "key_a");
+        assert_eq!(roles_and_contents(&chat.messages), [("user", "one"), ("assistant", "ok")]);
+        send(&mut chat, "two").await.unwrap();
+        
+        let chat = session(&config, &store, "key_a");
+        assert_eq!(
+            roles_and_contents(&chat.messages),
+            [("user", "one"), ("assistant", "ok"), ("user", "two"), ("assistant", "ok")]
+        );
+        
+        // Reloaded history isn't logged again
+        let log = dir.path().join("key_a").join(format!("chat_{}.log", Local::now().format("%Y-%m-%d")));
+        let entries: Vec<serde_json::Value> = std::fs::read_to_string(log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .filter(|entry: &serde_json::Value| entry.get("event").is_none())
+            .collect();
+        let logged: Vec<(&str, &str)> =
+            entries.iter().map(|e| (e["role"].as_str().unwrap(), e["content"].as_str().unwrap())).collect();
+        assert_eq!(logged, [("user", "lost"), ("user", "one"), ("assistant", "ok"), ("user", "two"), ("assistant", "ok")]);
+    }
+    
+    /// Whether `/help` output lists `name` as a command of its own
+    fn lists(help: &str, name: &str) -> bool {
+        help.lines().any(|line| line.trim_start().split(' ').next() == Some(name))
+    }
+    
+    #[tokio::test]
+    async fn every_command_is_dispatched_and_listed_in_help() {
+        let backend = MockBackend::start(vec![openai_answer("ok")]).await;
+        let dir = TempDir::new();
+        let config = Arc::new(Config {
+            logs_dir: dir.path().to_path_buf(),
+            admin_keys: vec!["abc".to_string()],
+            ..config(&backend.url)
+        });
+        let store = LogStore::default();
+        let help = help_text(&config, "key_abc");
+        
+        for command in COMMANDS {
+            assert!(lists(&help, command.name), "{} is missing from /help", command.name);
+            for name in std::iter::once(command.name).chain(command.aliases.iter().copied()) {
+                let reply = send(&mut session(&config, &store, "key_abc"), name).await.unwrap_or_else(|e| e);
+                assert!(!reply.starts_with("Unknown command"), "{} isn't dispatched", name);
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn help_leaves_out_commands_the_user_cannot_run() {
+        let backend = MockBackend::start(vec![openai_answer("ok")]).await;
+        let dir = TempDir::new();
+        let kiosk = Arc::new(Config {
+            logs_dir: dir.path().to_path_buf(),
+            admin_keys: vec!["abc".to_string()],
+            kiosk: true,
+            ..config(&backend.url)
+        });
+        let store = LogStore::default();
+        let help = help_text(&kiosk, "key_abc");
+        
+        for command in COMMANDS {
+            assert_eq!(lists(&help, command.name), !command.kiosk_disabled, "{} in kiosk /help", command.name);
+            let reply = send(&mut session(&kiosk, &store, "key_abc"), command.name).await.unwrap_or_else(|e| e);
+            assert_eq!(reply == "This command is disabled on this server.", command.kiosk_disabled, "{} in kiosk mode", command.name);
+        }
+        for name in ["/system", "/persona", "/model", "/models", "/maxtokens", "/uptime"] {
+            assert!(!lists(&help, name), "{} is available in kiosk mode", name);
+        }
+        
+        let config = Config { admin_keys: vec!["abc".to_string()], ..config(&backend.url) };
+        let help = help_text(&config, "key_other");
+        for command in COMMANDS {
+            assert_eq!(lists(&help, command.name), !command.operator, "{} in /help for non-operators", command.name);
+        }
+    }
+    
+    #[tokio::test]
+    async fn context_length_retry_drops_whole_exchanges() {
+        let too_long = json_response("400 Bad Request", r#"{"error":{"message":"maximum context length exceeded"}}"#);
+        let backend = MockBackend::start(vec![
+            openai_answer("a1"),
+            openai_answer("a2"),
+            openai_answer("a3"),
+            too_long,
+            openai_answer("a4"),
+        ])
+        .await;
+        let dir = TempDir::new();
+        let config = Arc::new(Config { logs_dir: dir.path().to_path_buf(), ..config(&backend.url) });
+        let mut chat = session(&config, &LogStore::default(), "key_a");
+        for input in ["q1", "q2", "q3"] {
+            send(&mut chat, input).await.unwrap();
+        }
+        
+        let reply = send(&mut chat, "q4").await.unwrap();
+        assert!(reply.contains("dropped 2 older messages"), "{}", reply);
+        assert_eq!(
+            roles_and_contents(&chat.messages),
+            [("user", "q2"), ("assistant", "a2"), ("user", "q3"), ("assistant", "a3"), ("user", "q4"), ("assistant", "a4")]
+        );
+    }
+    
+    #[test]
+    fn request_size_limit_drops_whole_exchanges() {
+        let dir = TempDir::new();
+        let history = [("user", "q1"), ("assistant", "a1"), ("user", "q2"), ("assistant", "a2")];
+        let chat_limited_to = |max_request_bytes| {
+            let config = Config { logs_dir: dir.path().to_path_buf(), max_request_bytes, ..config("http://127.0.0.1:9") };
+            let mut chat = session(&Arc::new(config), &LogStore::default(), "key_a");
+            chat.messages = history.iter().map(|(role, content)| Message::new(role, *content)).collect();
+            chat
+        };
+        let chat = chat_limited_to(None);
+        let full = chat.build_messages("q3").unwrap();
+        
+        // Room for everything but one byte
+        let size = chat.llm.request_size(&chat.params(), &full);
+        let msgs = chat_limited_to(Some(size - 1)).build_messages("q3").unwrap();
+        assert_eq!(roles_and_contents(&msgs[1..]), [("user", "q2"), ("assistant", "a2"), ("user", "q3")]);
+        
+        // No room even for the new message alone
+        let err = chat_limited_to(Some(10)).build_messages("q3").unwrap_err();
+        assert!(err.starts_with("Message too large"), "{}", err);
+    }
+    
+    #[tokio::test]
+    async fn odd_history_cap_keeps_whole_exchanges() {
+        let backend = MockBackend::start(vec![openai_answer("a1"), openai_answer("a2"), openai_answer("a3")]).await;
+        let dir = TempDir::new();
+        let config = Arc::new(Config { logs_dir: dir.path().to_path_buf(), max_history_messages: 3, ..config(&backend.url) });
+        let mut chat = session(&config, &LogStore::default(), "key_a");
+        for input in ["q1", "q2", "q3"] {
+            send(&mut chat, input).await.unwrap();
         }
+        assert_eq!(roles_and_contents(&chat.messages), [("user", "q3"), ("assistant", "a3")]);
     }
 }
@@ -1,10 +1,138 @@
-use std::path::PathBuf;
+use crate::i18n::{Translation, Translations};
+use crate::llm::Backend;
+use crate::logger::LogBackend;
+use crate::personas::Personas;
+use crate::rewrite::RewriteRule;
+use russh::{MethodKind, MethodSet};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Settings from a `--config` TOML file; flags and environment variables take precedence
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub api_base_url: Option<String>,
+    pub fallback_endpoints: Option<Vec<String>>,
+    pub model: Option<String>,
+    pub api_key: Option<String>,
+    pub system_prompt: Option<String>,
+    pub logs_dir: Option<PathBuf>,
+    pub host_key_path: Option<PathBuf>,
+}
+
+impl FileConfig {
+    /// Parse a config file; errors name the offending key and line
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
 
 pub struct Config {
+    /// Address the SSH server listens on, IPv4 or IPv6
+    pub bind: IpAddr,
     pub port: u16,
     pub api_base_url: String,
+    /// Tried in order when the primary endpoint can't be reached or returns a 5xx
+    pub fallback_endpoints: Vec<String>,
+    pub backend: Backend,
+    pub azure_deployment: Option<String>,
+    pub azure_api_version: String,
     pub model: String,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub seed: Option<u64>,
     pub api_key: Option<String>,
+    /// Sent with every LLM request, from `--header KEY=VALUE`
+    pub extra_headers: HashMap<String, String>,
+    /// Proxy for LLM requests; without one the standard proxy variables apply
+    pub proxy: Option<String>,
+    /// Extra roots trusted for HTTPS endpoints, from `--ca-cert`
+    pub ca_certs: Vec<reqwest::Certificate>,
+    pub insecure_skip_verify: bool,
     pub system_prompt: String,
+    pub input_template: Option<String>,
+    pub prompt_string: String,
+    pub translations: Translations,
+    pub max_request_bytes: Option<usize>,
+    pub request_timeout_secs: u64,
+    pub max_retries: u32,
+    pub retry_on_context_error: bool,
+    pub allow_model_ansi: bool,
+    pub rewrite_rules: Vec<RewriteRule>,
+    pub personas: Personas,
+    pub enable_tools: bool,
+    pub stream: bool,
     pub logs_dir: PathBuf,
+    pub log_backend: LogBackend,
+    /// Host keys offered to clients; missing ones are generated on start
+    pub host_key_paths: Vec<PathBuf>,
+    pub on_connect_cmd: Option<String>,
+    pub on_disconnect_cmd: Option<String>,
+    pub admin_keys: Vec<String>,
+    pub kiosk: bool,
+    pub max_conns_per_ip: u32,
+    pub max_concurrent_llm: usize,
+    /// Answers kept in the response cache; 0 when caching is off
+    pub cache_size: usize,
+    pub max_sessions: usize,
+    pub idle_timeout_secs: u64,
+    /// How long a key user's session outlives a dropped connection for them to reattach (0 disables)
+    pub reattach_grace_secs: u64,
+    /// Messages one identity may send per day (0 means unlimited)
+    pub daily_message_limit: u32,
+    pub authorized_keys: Option<PathBuf>,
+    pub require_key: bool,
+    pub trust_proxy: bool,
+    pub summarize_after: usize,
+    pub max_history_messages: usize,
+    pub max_loaded_history: usize,
+    pub max_log_bytes: u64,
+    pub log_retention_days: u32,
+    pub context_size: usize,
+    pub banner: Option<PathBuf>,
+    pub color: bool,
+    pub keepalive_secs: u64,
+    /// Delay before a failed authentication is answered, slowing down guessing
+    pub auth_rejection_ms: u64,
+    /// Failed authentications after which a connection is dropped (0 disables)
+    pub max_auth_attempts: u32,
+}
+
+impl Config {
+    /// Translation for a language code, if one was configured
+    pub fn translation(&self, language: Option<&str>) -> Option<&Translation> {
+        language.and_then(|lang| self.translations.get(lang))
+    }
+
+    /// The primary endpoint followed by the fallbacks, in the order they're tried
+    pub fn endpoints(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.api_base_url.as_str()).chain(self.fallback_endpoints.iter().map(String::as_str))
+    }
+
+    /// Whether logins must use a public key (no password/guest access)
+    pub fn requires_key(&self) -> bool {
+        self.require_key || self.authorized_keys.is_some()
+    }
+
+    /// Authentication methods offered to clients
+    pub fn auth_methods(&self) -> MethodSet {
+        if self.requires_key() {
+            MethodSet::from(&[MethodKind::PublicKey][..])
+        } else {
+            MethodSet::from(&[MethodKind::PublicKey, MethodKind::Password][..])
+        }
+    }
+
+    /// Whether the given identity belongs to a configured operator key
+    pub fn is_admin(&self, identity: &str) -> bool {
+        let fingerprint = identity.strip_prefix("key_").unwrap_or(identity);
+        identity.starts_with("key_")
+            && self.admin_keys.iter().any(|k| k.strip_prefix("key_").unwrap_or(k) == fingerprint)
+    }
 }
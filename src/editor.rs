@@ -0,0 +1,169 @@
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Escape code moving the terminal cursor left by `columns`
+fn left(columns: usize) -> String {
+    // `\x1b[0D` still moves one column in most terminals
+    if columns == 0 {
+        String::new()
+    } else {
+        format!("\x1b[{}D", columns)
+    }
+}
+
+/// Escape code moving the terminal cursor right by `columns`
+fn right(columns: usize) -> String {
+    if columns == 0 {
+        String::new()
+    } else {
+        format!("\x1b[{}C", columns)
+    }
+}
+
+/// The line being typed, with a cursor that moves by character.
+///
+/// Each edit returns the bytes to echo so the client's screen matches the
+/// buffer; columns are measured with `unicode-width` so wide characters work.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    text: String,
+    /// Cursor position in characters, `0..=text.chars().count()`
+    cursor: usize,
+}
+
+impl LineEditor {
+    /// Take the line for submission, leaving an empty editor
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    /// Replace the line and put the cursor at its end (the caller redraws)
+    pub fn set(&mut self, text: String) {
+        self.cursor = text.chars().count();
+        self.text = text;
+    }
+
+    /// The whole line with the cursor put back in place, for reprinting after a prompt
+    pub fn redraw(&self) -> String {
+        format!("{}{}", self.text, left(self.tail().width()))
+    }
+
+    /// Byte offset of a character position
+    fn offset(&self, cursor: usize) -> usize {
+        self.text
+            .char_indices()
+            .nth(cursor)
+            .map(|(i, _)| i)
+            .unwrap_or(self.text.len())
+    }
+
+    /// Text from the cursor to the end of the line
+    fn tail(&self) -> &str {
+        &self.text[self.offset(self.cursor)..]
+    }
+
+    /// Insert text at the cursor
+    pub fn insert(&mut self, text: &str) -> String {
+        let offset = self.offset(self.cursor);
+        self.text.insert_str(offset, text);
+        self.cursor += text.chars().count();
+
+        // Reprint the tail after the new text, then step back over it
+        let tail = self.tail();
+        format!("{}{}{}", text, tail, left(tail.width()))
+    }
+
+    /// Remove the `count` characters before the cursor
+    fn remove_before(&mut self, count: usize) -> String {
+        if count == 0 {
+            return String::new();
+        }
+        let end = self.offset(self.cursor);
+        self.cursor -= count;
+        let start = self.offset(self.cursor);
+        let width = self.text[start..end].width();
+        self.text.replace_range(start..end, "");
+
+        // Shift the tail left and blank the columns it vacated
+        let tail = self.tail();
+        format!("{}{}{}{}", left(width), tail, " ".repeat(width), left(tail.width() + width))
+    }
+
+    /// Delete the character before the cursor
+    pub fn backspace(&mut self) -> String {
+        self.remove_before(self.cursor.min(1))
+    }
+
+    /// Delete back to the start of the previous word (Ctrl+W)
+    pub fn delete_word(&mut self) -> String {
+        let before: Vec<char> = self.text[..self.offset(self.cursor)].chars().collect();
+        let spaces = before.iter().rev().take_while(|c| c.is_whitespace()).count();
+        let word = before[..before.len() - spaces]
+            .iter()
+            .rev()
+            .take_while(|c| !c.is_whitespace())
+            .count();
+        self.remove_before(spaces + word)
+    }
+
+    /// Empty the line (Ctrl+U)
+    pub fn clear(&mut self) -> String {
+        let width = self.text[..self.offset(self.cursor)].width();
+        self.text.clear();
+        self.cursor = 0;
+        format!("{}\x1b[K", left(width))
+    }
+
+    /// Delete the character under the cursor
+    pub fn delete(&mut self) -> String {
+        let offset = self.offset(self.cursor);
+        if offset == self.text.len() {
+            return String::new();
+        }
+        let width = self.text.remove(offset).width().unwrap_or(0);
+        
+        // Pull the tail back over it and blank the freed columns
+        let tail = self.tail();
+        format!("{}{}{}", tail, " ".repeat(width), left(tail.width() + width))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.offset(self.cursor) == self.text.len()
+    }
+
+    pub fn move_left(&mut self) -> String {
+        if self.cursor == 0 {
+            return String::new();
+        }
+        self.cursor -= 1;
+        left(self.tail().chars().next().and_then(|c| c.width()).unwrap_or(0))
+    }
+
+    pub fn move_right(&mut self) -> String {
+        let Some(c) = self.tail().chars().next() else {
+            return String::new();
+        };
+        self.cursor += 1;
+        right(c.width().unwrap_or(0))
+    }
+
+    pub fn home(&mut self) -> String {
+        let width = self.text[..self.offset(self.cursor)].width();
+        self.cursor = 0;
+        left(width)
+    }
+
+    pub fn end(&mut self) -> String {
+        let width = self.tail().width();
+        self.cursor = self.text.chars().count();
+        right(width)
+    }
+}
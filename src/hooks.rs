@@ -0,0 +1,60 @@
+use chrono::Utc;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// How long a hook may run before it is killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run a connect/disconnect hook in the background, passing identity and timestamp
+pub fn spawn_hook(program: &str, event: &'static str, identity: &str) {
+    let program = program.to_string();
+    let identity = identity.to_string();
+    let timestamp = Utc::now().to_rfc3339();
+
+    tokio::spawn(async move {
+        let child = Command::new(&program)
+            .arg(&identity)
+            .arg(&timestamp)
+            .env("SSHLLM_EVENT", event)
+            .env("SSHLLM_IDENTITY", &identity)
+            .env("SSHLLM_TIMESTAMP", &timestamp)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run {} hook {}: {}", event, program, e);
+                return;
+            }
+        };
+
+        // Dropping the child on timeout kills it
+        match tokio::time::timeout(HOOK_TIMEOUT, child.wait_with_output()).await {
+            Ok(Ok(output)) if output.status.success() => {
+                info!("{} hook for {} finished", event, identity);
+            }
+            Ok(Ok(output)) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                warn!(
+                    "{} hook {} for {} exited with {}: {}",
+                    event,
+                    program,
+                    identity,
+                    output.status,
+                    stderr.trim()
+                );
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to wait for {} hook {}: {}", event, program, e);
+            }
+            Err(_) => {
+                warn!("{} hook {} for {} killed after {}s", event, program, identity, HOOK_TIMEOUT.as_secs());
+            }
+        }
+    });
+}
@@ -0,0 +1,103 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Localized UI strings for one language; unset entries fall back to English
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Translation {
+    /// Greeting for new users
+    pub welcome: Option<String>,
+    /// Greeting for returning users, `{name}` is replaced with their name
+    pub welcome_back: Option<String>,
+    /// Input prompt, e.g. `Tú: `
+    pub prompt: Option<String>,
+}
+
+/// Translations keyed by language code (`es`, `de`, ...)
+pub type Translations = HashMap<String, Translation>;
+
+/// Load translations from a TOML file with one table per language code
+pub fn load_translations(path: &Path) -> anyhow::Result<Translations> {
+    let contents = std::fs::read_to_string(path)?;
+    let translations: Translations = toml::from_str(&contents)?;
+    Ok(translations
+        .into_iter()
+        .map(|(code, t)| (code.to_lowercase(), t))
+        .collect())
+}
+
+/// Locale variables accepted from SSH `env` requests, highest precedence first
+pub const LOCALE_VARIABLES: &[&str] = &["LC_ALL", "LC_MESSAGES", "LANG"];
+
+/// Language of the forwarded locale variables, following their precedence
+pub fn language_from_env(env: &HashMap<String, String>) -> Option<String> {
+    LOCALE_VARIABLES
+        .iter()
+        .find_map(|name| env.get(*name).filter(|value| !value.is_empty()))
+        .and_then(|locale| language_from_locale(locale))
+}
+
+/// Language code from a locale string like `es_ES.UTF-8`; `None` for `C`/`POSIX`
+pub fn language_from_locale(locale: &str) -> Option<String> {
+    let lang = locale
+        .split(['_', '.', '@', '-'])
+        .next()?
+        .to_lowercase();
+
+    match lang.as_str() {
+        "" | "c" | "posix" => None,
+        _ => Some(lang),
+    }
+}
+
+/// Languages `/lang` accepts, by code
+const LANGUAGE_NAMES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("cs", "Czech"),
+    ("de", "German"),
+    ("el", "Greek"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("he", "Hebrew"),
+    ("hi", "Hindi"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ru", "Russian"),
+    ("sv", "Swedish"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("zh", "Chinese"),
+];
+
+/// English name of a language code, e.g. `pl` -> `Polish`
+pub fn language_name(code: &str) -> Option<&'static str> {
+    LANGUAGE_NAMES.iter().find(|(c, _)| *c == code).map(|(_, name)| *name)
+}
+
+/// Codes `language_name` knows, for usage messages
+pub fn language_codes() -> Vec<&'static str> {
+    LANGUAGE_NAMES.iter().map(|(code, _)| *code).collect()
+}
+
+/// Guess a language from the script used in a message (non-Latin scripts only)
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    // Kana means Japanese even when the text starts with kanji
+    if text.chars().any(|c| ('\u{3040}'..='\u{30FF}').contains(&c)) {
+        return Some("ja");
+    }
+
+    text.chars().find_map(|c| match c {
+        '\u{0400}'..='\u{04FF}' => Some("ru"),
+        '\u{0370}'..='\u{03FF}' => Some("el"),
+        '\u{0590}'..='\u{05FF}' => Some("he"),
+        '\u{0600}'..='\u{06FF}' => Some("ar"),
+        '\u{AC00}'..='\u{D7AF}' => Some("ko"),
+        '\u{4E00}'..='\u{9FFF}' => Some("zh"),
+        _ => None,
+    })
+}
@@ -0,0 +1,114 @@
+use chrono::{Local, NaiveDate};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Window over which connection attempts are counted
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Tracked addresses above which idle entries are pruned
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Sliding one-minute window of accepted connections per source IP
+pub struct ConnectionLimiter {
+    /// Connections allowed per IP per minute; 0 disables the limit
+    max_per_minute: u32,
+    attempts: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_minute: u32) -> Self {
+        Self {
+            max_per_minute,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a connection from `ip`, or return false if it is over the limit
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        if self.max_per_minute == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+
+        if attempts.len() > PRUNE_THRESHOLD {
+            attempts.retain(|_, times| times.back().is_some_and(|t| now.duration_since(*t) < WINDOW));
+        }
+
+        let times = attempts.entry(ip).or_default();
+        while times.front().is_some_and(|t| now.duration_since(*t) >= WINDOW) {
+            times.pop_front();
+        }
+
+        if times.len() >= self.max_per_minute as usize {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// Messages each identity sent today, for sessions that keep no stored summary (kiosk mode)
+#[derive(Default)]
+pub struct DailyCounts {
+    counts: Mutex<HashMap<String, (NaiveDate, u32)>>,
+}
+
+impl DailyCounts {
+    /// Count a message from `identity`, or return false if it already sent `limit` today
+    pub fn take(&self, identity: &str, limit: u32) -> bool {
+        let today = Local::now().date_naive();
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts.retain(|_, (day, _)| *day == today);
+        let (_, sent) = counts.entry(identity.to_string()).or_insert((today, 0));
+        if *sent >= limit {
+            return false;
+        }
+        *sent += 1;
+        true
+    }
+}
+
+/// Bounds LLM requests in flight across all sessions; extra requests wait in arrival order
+pub struct RequestQueue {
+    /// `None` when requests are unlimited
+    slots: Option<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+/// Counts a request as queued until it gets a slot or gives up
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RequestQueue {
+    /// Allow `max_concurrent` requests at once; 0 disables the limit
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            slots: (max_concurrent > 0).then(|| Semaphore::new(max_concurrent)),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for a free slot, held until the permit is dropped; `on_queued` gets the
+    /// request's place in line when it can't start right away
+    pub async fn acquire(&self, on_queued: impl FnOnce(usize)) -> Option<SemaphorePermit<'_>> {
+        let slots = self.slots.as_ref()?;
+        if let Ok(permit) = slots.try_acquire() {
+            return Some(permit);
+        }
+        let position = self.waiting.fetch_add(1, Ordering::Relaxed) + 1;
+        let _waiting = Waiting(&self.waiting);
+        on_queued(position);
+        slots.acquire().await.ok()
+    }
+}
@@ -1,24 +1,206 @@
+use crate::cache::ResponseCache;
 use crate::config::Config;
-use reqwest::Client;
+use crate::tools::ToolRegistry;
+use reqwest::{Client, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Tool-call round trips allowed before giving up on a final answer
+const MAX_TOOL_ROUNDS: usize = 5;
+
+/// Anthropic requires `max_tokens`; used when none is configured
+const ANTHROPIC_DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// API version sent in the `anthropic-version` header
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Wire protocol spoken by the LLM endpoint
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// OpenAI-compatible `/chat/completions`
+    #[default]
+    #[value(name = "openai")]
+    OpenAiCompatible,
+    /// Anthropic Messages API (`/messages`)
+    Anthropic,
+    /// Ollama's native `/api/chat`, streamed as newline-delimited JSON
+    Ollama,
+    /// Azure OpenAI: the OpenAI schema on `/openai/deployments/{deployment}/chat/completions`
+    Azure,
+}
+
+impl Backend {
+    /// Whether requests and responses follow the OpenAI chat completions schema
+    pub fn openai_schema(self) -> bool {
+        matches!(self, Backend::OpenAiCompatible | Backend::Azure)
+    }
+    
+    /// Path appended to the endpoint for chat requests (Azure puts the deployment in front)
+    pub fn chat_path(self) -> &'static str {
+        match self {
+            Backend::OpenAiCompatible | Backend::Azure => "/chat/completions",
+            Backend::Anthropic => "/messages",
+            Backend::Ollama => "/api/chat",
+        }
+    }
+}
+
+/// Wait before the first retry of a failed request; doubles on each further retry
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest wait between retries, however many there are
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Backoff before retry number `attempt` (counting from 0)
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_RETRY_DELAY)
+}
+
+/// A failed request attempt
+struct SendError {
+    message: String,
+    /// Network error, 5xx or 429: worth trying again
+    transient: bool,
+    /// Network error or 5xx: the next endpoint may do better
+    failover: bool,
+    /// Delay requested by the server via `Retry-After`
+    retry_after: Option<Duration>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub fn new(role: &str, content: impl Into<String>) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.into(),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// Bytes this message adds to a request body, counting the comma that separates it
+    pub fn request_len(&self) -> usize {
+        serde_json::to_vec(self).map(|json| json.len() + 1).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "default_tool_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: String,
+}
+
+fn default_tool_type() -> String {
+    "function".to_string()
+}
+
+/// Model and sampling settings for a request; unset values use the backend's defaults
+#[derive(Debug, Clone, Default)]
+pub struct GenerationParams {
+    pub model: String,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u32>,
+    /// Asks backends that support it for reproducible sampling
+    pub seed: Option<u64>,
+    /// Whether the answer to an identical earlier request may be reused
+    pub use_cache: bool,
 }
 
 #[derive(Debug, Serialize)]
-struct ChatRequest {
-    model: String,
-    messages: Vec<Message>,
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [Message],
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    /// Ask for a final chunk carrying token usage
+    include_usage: bool,
+}
+
+/// Token counts for one response
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    fn new(prompt_tokens: u64, completion_tokens: u64) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+    
+    /// Combine partial reports from one stream, where counts are cumulative
+    fn merge(self, other: Usage) -> Self {
+        let mut merged = Self::new(
+            self.prompt_tokens.max(other.prompt_tokens),
+            self.completion_tokens.max(other.completion_tokens),
+        );
+        merged.total_tokens = merged.total_tokens.max(self.total_tokens).max(other.total_tokens);
+        merged
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct ChatResponse {
     choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+    #[serde(default)]
+    system_fingerprint: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,60 +210,1023 @@ struct Choice {
 
 #[derive(Debug, Deserialize)]
 struct ResponseMessage {
-    content: String,
+    #[serde(default)]
+    content: Option<String>,
+    /// Separate reasoning field used by some vLLM/DeepSeek deployments
+    #[serde(default, alias = "reasoning")]
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Option<Vec<ToolCall>>,
+}
+
+#[derive(Debug, Serialize)]
+struct AnthropicRequest<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage<'a>>,
+    max_tokens: u32,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct AnthropicMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+impl<'a> AnthropicRequest<'a> {
+    /// System messages become the top-level `system` field
+    fn new(params: &'a GenerationParams, messages: &'a [Message], stream: bool) -> Self {
+        let system: Vec<&str> = messages
+            .iter()
+            .filter(|m| m.role == "system")
+            .map(|m| m.content.as_str())
+            .collect();
+        Self {
+            model: &params.model,
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages: messages
+                .iter()
+                .filter(|m| m.role != "system")
+                .map(|m| AnthropicMessage { role: &m.role, content: &m.content })
+                .collect(),
+            max_tokens: params.max_tokens.unwrap_or(ANTHROPIC_DEFAULT_MAX_TOKENS),
+            stream,
+            temperature: params.temperature,
+            top_p: params.top_p,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicResponse {
+    #[serde(default)]
+    content: Vec<AnthropicBlock>,
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+impl From<AnthropicUsage> for Usage {
+    fn from(usage: AnthropicUsage) -> Self {
+        Usage::new(usage.input_tokens, usage.output_tokens)
+    }
+}
+
+/// Content block of a response, or the delta of one in a stream
+#[derive(Debug, Default, Deserialize)]
+struct AnthropicBlock {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    thinking: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicStreamEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    delta: Option<AnthropicBlock>,
+    /// Set on `message_start`
+    #[serde(default)]
+    message: Option<AnthropicResponse>,
+    /// Set on `message_delta`
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
+    #[serde(default)]
+    error: Option<AnthropicError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicError {
+    #[serde(default)]
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaRequest<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<OllamaOptions>,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+}
+
+impl<'a> OllamaRequest<'a> {
+    fn new(params: &'a GenerationParams, messages: &'a [Message], stream: bool) -> Self {
+        let has_options = params.temperature.is_some()
+            || params.top_p.is_some()
+            || params.max_tokens.is_some()
+            || params.seed.is_some();
+        Self {
+            model: &params.model,
+            messages,
+            stream,
+            options: has_options.then_some(OllamaOptions {
+                temperature: params.temperature,
+                top_p: params.top_p,
+                num_predict: params.max_tokens,
+                seed: params.seed,
+            }),
+        }
+    }
+}
+
+/// Whole response, or one line of a streamed one
+#[derive(Debug, Deserialize)]
+struct OllamaChunk {
+    #[serde(default)]
+    message: Option<OllamaMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
+}
+
+impl OllamaChunk {
+    /// Token counts, present on the final chunk
+    fn usage(&self) -> Option<Usage> {
+        if self.prompt_eval_count.is_none() && self.eval_count.is_none() {
+            return None;
+        }
+        Some(Usage::new(self.prompt_eval_count.unwrap_or(0), self.eval_count.unwrap_or(0)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaMessage {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    thinking: Option<String>,
+}
+
+/// Response from Ollama's `GET /api/tags`
+#[derive(Debug, Deserialize)]
+struct OllamaTags {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+/// Response from `GET /models`
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+/// Whether an error from `chat` means the prompt exceeded the model's context window
+pub fn is_context_length_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    [
+        "context_length_exceeded",
+        "maximum context length",
+        "context length",
+        "context window",
+        "prompt is too long",
+    ]
+    .iter()
+    .any(|pattern| error.contains(pattern))
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+    #[serde(default)]
+    system_fingerprint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: StreamDelta,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StreamDelta {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default, alias = "reasoning")]
+    reasoning_content: Option<String>,
+}
+
+/// Incremental output from `chat_stream`
+pub enum StreamEvent {
+    Content(String),
+    Reasoning(String),
+}
+
+/// Tags reasoning models (DeepSeek-R1 style) wrap their reasoning in, inline in the answer
+const THINK_OPEN: &str = "<think>";
+const THINK_CLOSE: &str = "</think>";
+
+/// Error for a response that ended before the model got past its reasoning
+const REASONING_ONLY: &str = "The model returned reasoning but no answer (it may have hit the token limit)";
+
+/// Moves inline `<think>...</think>` blocks out of answer text and into reasoning,
+/// coping with tags split across stream deltas; an unterminated block runs to the end
+#[derive(Default)]
+struct ThinkSplitter {
+    in_think: bool,
+    /// Whitespace after a closing tag is dropped until the answer starts
+    after_think: bool,
+    /// Text held back because it may be the start of a tag
+    pending: String,
+}
+
+impl ThinkSplitter {
+    /// Split a content delta into reasoning and content events
+    fn push(&mut self, text: &str) -> Vec<StreamEvent> {
+        self.pending.push_str(text);
+        let mut events = Vec::new();
+        loop {
+            let tag = if self.in_think { THINK_CLOSE } else { THINK_OPEN };
+            if let Some(pos) = self.pending.find(tag) {
+                let before: String = self.pending.drain(..pos).collect();
+                self.pending.drain(..tag.len());
+                self.emit(&mut events, before);
+                self.after_think = self.in_think;
+                self.in_think = !self.in_think;
+                continue;
+            }
+            let held = (1..tag.len()).rev().find(|&n| self.pending.ends_with(&tag[..n])).unwrap_or(0);
+            let text: String = self.pending.drain(..self.pending.len() - held).collect();
+            self.emit(&mut events, text);
+            return events;
+        }
+    }
+    
+    /// Release any text held back at the end of the response
+    fn finish(&mut self) -> Vec<StreamEvent> {
+        let text = std::mem::take(&mut self.pending);
+        let mut events = Vec::new();
+        self.emit(&mut events, text);
+        events
+    }
+    
+    fn emit(&mut self, events: &mut Vec<StreamEvent>, text: String) {
+        if self.in_think {
+            if !text.is_empty() {
+                events.push(StreamEvent::Reasoning(text));
+            }
+            return;
+        }
+        let text = if self.after_think { text.trim_start().to_string() } else { text };
+        if !text.is_empty() {
+            self.after_think = false;
+            events.push(StreamEvent::Content(text));
+        }
+    }
+}
+
+/// Separate inline `<think>` blocks from a complete answer, as (answer, reasoning)
+fn split_think(content: &str) -> (String, String) {
+    let mut splitter = ThinkSplitter::default();
+    let mut events = splitter.push(content);
+    events.extend(splitter.finish());
+    let (mut answer, mut reasoning) = (String::new(), String::new());
+    for event in events {
+        match event {
+            StreamEvent::Content(text) => answer.push_str(&text),
+            StreamEvent::Reasoning(text) => reasoning.push_str(&text),
+        }
+    }
+    (answer, reasoning)
+}
+
+/// Reasoning from a separate field and from inline tags, whichever are present
+fn join_reasoning(field: Option<String>, inline: String) -> Option<String> {
+    let parts: Vec<String> = field.into_iter().chain([inline]).filter(|r| !r.trim().is_empty()).collect();
+    (!parts.is_empty()).then(|| parts.join("\n\n"))
+}
+
+/// What one line of a streamed response carried
+#[derive(Default)]
+struct StreamLine {
+    events: Vec<StreamEvent>,
+    usage: Option<Usage>,
+    system_fingerprint: Option<String>,
+    done: bool,
+}
+
+/// Final answer from `chat`
+#[derive(Debug, Clone, Default)]
+pub struct ChatOutcome {
+    pub content: String,
+    /// Reasoning the backend returned separately from the answer
+    pub reasoning: Option<String>,
+    /// Tokens used, if the backend reported them
+    pub usage: Option<Usage>,
+    /// Backend configuration the answer came from (OpenAI `system_fingerprint`), if reported
+    pub system_fingerprint: Option<String>,
 }
 
 pub struct LlmClient {
     client: Client,
     config: Arc<Config>,
+    tools: Option<ToolRegistry>,
+    cache: Option<Arc<ResponseCache>>,
 }
 
 impl LlmClient {
-    pub fn new(config: Arc<Config>) -> Self {
-        Self {
-            client: Client::new(),
+    /// Client shared by every session; fails if the proxy or TLS settings can't be applied
+    pub fn new(config: Arc<Config>, cache: Option<Arc<ResponseCache>>) -> anyhow::Result<Self> {
+        // Tool calling is only implemented for the OpenAI request format
+        let tools = (config.enable_tools && config.backend.openai_schema())
+            .then(ToolRegistry::with_builtins);
+        // Streams may legitimately run longer than the timeout, so the client only
+        // bounds the gap between reads; whole requests are bounded in `send`
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_secs(config.request_timeout_secs))
+            .read_timeout(Duration::from_secs(config.request_timeout_secs))
+            .danger_accept_invalid_certs(config.insecure_skip_verify);
+        for cert in &config.ca_certs {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        // An explicit proxy replaces the environment's
+        if let Some(ref proxy) = config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        let client = builder.build()?;
+        Ok(Self {
+            client,
             config,
+            tools,
+            cache,
+        })
+    }
+    
+    fn openai_request<'a>(&'a self, params: &'a GenerationParams, messages: &'a [Message], stream: bool) -> ChatRequest<'a> {
+        ChatRequest {
+            model: &params.model,
+            messages,
+            stream,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            max_tokens: params.max_tokens,
+            seed: params.seed,
+            tools: self.tools.as_ref().map(|t| t.definitions()),
+            tool_choice: self.tools.as_ref().map(|_| "auto"),
+            stream_options: stream.then_some(StreamOptions { include_usage: true }),
+        }
+    }
+    
+    /// JSON body of a chat request in the backend's format
+    fn request_body(&self, params: &GenerationParams, messages: &[Message], stream: bool) -> serde_json::Value {
+        let body = match self.config.backend {
+            Backend::OpenAiCompatible | Backend::Azure => serde_json::to_value(self.openai_request(params, messages, stream)),
+            Backend::Anthropic => serde_json::to_value(AnthropicRequest::new(params, messages, stream)),
+            Backend::Ollama => serde_json::to_value(OllamaRequest::new(params, messages, stream)),
+        };
+        body.unwrap_or_default()
+    }
+    
+    /// Size in bytes of the JSON request body that `chat` would send
+    pub fn request_size(&self, params: &GenerationParams, messages: &[Message]) -> usize {
+        serde_json::to_vec(&self.request_body(params, messages, false)).map(|body| body.len()).unwrap_or(0)
+    }
+
+    /// Response cache key for a request, or `None` when it mustn't be cached: only
+    /// deterministic (temperature 0) requests are
+    fn cache_key(&self, params: &GenerationParams, messages: &[Message]) -> Option<u64> {
+        self.cache.as_ref()?;
+        if !params.use_cache || !params.temperature.is_some_and(|t| t <= 0.0) {
+            return None;
+        }
+        let body = serde_json::to_string(&self.request_body(params, messages, false)).ok()?;
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+    
+    /// Stored answer for `key`, if any
+    fn cached(&self, params: &GenerationParams, key: Option<u64>) -> Option<ChatOutcome> {
+        let outcome = self.cache.as_ref()?.get(key?)?;
+        info!(model = %params.model, "Answered from the response cache");
+        // Nothing was sent, so no tokens were used
+        Some(ChatOutcome { usage: None, ..outcome })
+    }
+    
+    fn store(&self, key: Option<u64>, outcome: &ChatOutcome) {
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            cache.insert(key, outcome.clone());
         }
     }
     
-    /// Send a chat request and get response (non-streaming)
-    pub async fn chat(&self, messages: Vec<Message>) -> Result<String, String> {
-        let url = format!("{}/chat/completions", self.config.api_base_url);
+    /// Send a chat request and get the final response (non-streaming),
+    /// running any tool calls the model makes along the way
+    pub async fn chat(&self, params: &GenerationParams, messages: Vec<Message>) -> Result<ChatOutcome, String> {
+        let key = self.cache_key(params, &messages);
+        if let Some(outcome) = self.cached(params, key) {
+            return Ok(outcome);
+        }
+        let outcome = self.run_tool_rounds(params, messages).await?;
+        self.store(key, &outcome);
+        Ok(outcome)
+    }
+    
+    /// Ask until the model answers instead of calling tools
+    async fn run_tool_rounds(&self, params: &GenerationParams, mut messages: Vec<Message>) -> Result<ChatOutcome, String> {
+        // Tool rounds all count towards the turn's usage
+        let mut usage: Option<Usage> = None;
         
-        let request = ChatRequest {
-            model: self.config.model.clone(),
-            messages,
-            stream: false,
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let (message, round_usage, system_fingerprint) = self.complete(params, &messages).await?;
+            if let Some(round_usage) = round_usage {
+                *usage.get_or_insert_default() += round_usage;
+            }
+            
+            let (Some(tools), Some(tool_calls)) = (&self.tools, message.tool_calls.filter(|c| !c.is_empty())) else {
+                let content = message.content.ok_or_else(|| "No response from LLM".to_string())?;
+                let (content, inline_reasoning) = split_think(&content);
+                let reasoning = join_reasoning(message.reasoning_content, inline_reasoning);
+                if content.trim().is_empty() && reasoning.is_some() {
+                    return Err(REASONING_ONLY.to_string());
+                }
+                return Ok(ChatOutcome { content, reasoning, usage, system_fingerprint });
+            };
+            
+            let mut assistant = Message::new("assistant", message.content.unwrap_or_default());
+            assistant.tool_calls = Some(tool_calls.clone());
+            messages.push(assistant);
+            
+            for call in tool_calls {
+                info!("Running tool {} for model", call.function.name);
+                let mut result = Message::new("tool", tools.call(&call.function.name, &call.function.arguments));
+                result.tool_call_id = Some(call.id);
+                messages.push(result);
+            }
+        }
+        
+        Err("Model kept calling tools without giving an answer".to_string())
+    }
+    
+    /// Send a chat request with `stream: true`, passing deltas to `on_event` as
+    /// they arrive and returning the assembled answer.
+    ///
+    /// Tool calls aren't streamed: with tools enabled this runs `chat` and
+    /// reports the whole answer as a single delta, as it does for cached answers.
+    pub async fn chat_stream(
+        &self,
+        params: &GenerationParams,
+        messages: Vec<Message>,
+        mut on_event: impl FnMut(StreamEvent) + Send,
+    ) -> Result<ChatOutcome, String> {
+        let key = self.cache_key(params, &messages);
+        let cached = self.cached(params, key);
+        if self.tools.is_some() || cached.is_some() {
+            let outcome = match cached {
+                Some(outcome) => outcome,
+                None => self.chat(params, messages).await?,
+            };
+            if let Some(ref reasoning) = outcome.reasoning {
+                on_event(StreamEvent::Reasoning(reasoning.clone()));
+            }
+            on_event(StreamEvent::Content(outcome.content.clone()));
+            return Ok(outcome);
+        }
+        
+        let mut response = self.send(params, &messages, true).await?;
+        let mut content = String::new();
+        let mut reasoning = String::new();
+        let mut usage: Option<Usage> = None;
+        let mut system_fingerprint = None;
+        let mut buffer = Vec::new();
+        let mut think = ThinkSplitter::default();
+        let mut record = |event: StreamEvent| {
+            match event {
+                StreamEvent::Reasoning(ref delta) => reasoning.push_str(delta),
+                StreamEvent::Content(ref delta) => content.push_str(delta),
+            }
+            on_event(event);
         };
         
-        let mut req = self.client.post(&url)
-            .header("Content-Type", "application/json");
+        'read: while let Some(chunk) = response.chunk().await.map_err(|e| self.request_error("Stream error", e))? {
+            buffer.extend_from_slice(&chunk);
+            
+            // Only complete lines are parsed, so multibyte characters never get split
+            while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                
+                let parsed = self.parse_stream_line(line.trim())?;
+                if let Some(line_usage) = parsed.usage {
+                    usage = Some(usage.map_or(line_usage, |u| u.merge(line_usage)));
+                }
+                if parsed.system_fingerprint.is_some() {
+                    system_fingerprint = parsed.system_fingerprint;
+                }
+                for event in parsed.events {
+                    match event {
+                        StreamEvent::Content(delta) => think.push(&delta).into_iter().for_each(&mut record),
+                        event => record(event),
+                    }
+                }
+                if parsed.done {
+                    break 'read;
+                }
+            }
+        }
+        think.finish().into_iter().for_each(record);
         
-        if let Some(ref api_key) = self.config.api_key {
-            req = req.header("Authorization", format!("Bearer {}", api_key));
+        if content.is_empty() {
+            let error = if reasoning.trim().is_empty() { "No response from LLM" } else { REASONING_ONLY };
+            return Err(error.to_string());
         }
         
-        let response = req
-            .json(&request)
+        let outcome = ChatOutcome {
+            content,
+            reasoning: Some(reasoning).filter(|r| !r.trim().is_empty()),
+            usage,
+            system_fingerprint,
+        };
+        self.store(key, &outcome);
+        Ok(outcome)
+    }
+    
+    /// Parse one line of a streamed response
+    fn parse_stream_line(&self, line: &str) -> Result<StreamLine, String> {
+        let mut parsed = StreamLine::default();
+        
+        // Ollama sends one JSON object per line; the others use server-sent
+        // events: data: {json} ... data: [DONE]
+        let data = match self.config.backend {
+            Backend::Ollama => line,
+            _ => match line.strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => return Ok(parsed),
+            },
+        };
+        if data.is_empty() {
+            return Ok(parsed);
+        }
+        
+        match self.config.backend {
+            Backend::OpenAiCompatible | Backend::Azure => {
+                if data == "[DONE]" {
+                    parsed.done = true;
+                    return Ok(parsed);
+                }
+                let chunk: StreamChunk = serde_json::from_str(data)
+                    .map_err(|e| format!("Parse error: {}", e))?;
+                for choice in chunk.choices {
+                    parsed.events.extend(choice.delta.reasoning_content.map(StreamEvent::Reasoning));
+                    parsed.events.extend(choice.delta.content.map(StreamEvent::Content));
+                }
+                parsed.usage = chunk.usage;
+                parsed.system_fingerprint = chunk.system_fingerprint;
+            }
+            Backend::Anthropic => {
+                let event: AnthropicStreamEvent = serde_json::from_str(data)
+                    .map_err(|e| format!("Parse error: {}", e))?;
+                match event.kind.as_str() {
+                    "message_stop" => parsed.done = true,
+                    "error" => {
+                        return Err(format!("API error: {}", event.error.map(|e| e.message).unwrap_or_default()));
+                    }
+                    _ => {}
+                }
+                if let Some(delta) = event.delta {
+                    parsed.events.extend(delta.thinking.map(StreamEvent::Reasoning));
+                    parsed.events.extend(delta.text.map(StreamEvent::Content));
+                }
+                // Input tokens arrive in message_start, output tokens in message_delta
+                parsed.usage = event.usage.or(event.message.and_then(|m| m.usage)).map(Usage::from);
+            }
+            Backend::Ollama => {
+                let chunk: OllamaChunk = serde_json::from_str(data)
+                    .map_err(|e| format!("Parse error: {}", e))?;
+                if let Some(error) = chunk.error {
+                    return Err(format!("API error: {}", error));
+                }
+                parsed.usage = chunk.usage();
+                if let Some(message) = chunk.message {
+                    parsed.events.extend(message.thinking.map(StreamEvent::Reasoning));
+                    parsed.events.extend(message.content.map(StreamEvent::Content));
+                }
+                parsed.done = chunk.done;
+            }
+        }
+        
+        parsed.events.retain(|e| !matches!(e, StreamEvent::Content(d) | StreamEvent::Reasoning(d) if d.is_empty()));
+        Ok(parsed)
+    }
+    
+    /// Model ids the backend offers, from `GET {api_base_url}/models` (`/api/tags` for Ollama)
+    pub async fn list_models(&self) -> Result<Vec<String>, String> {
+        let path = match self.config.backend {
+            Backend::Ollama => "api/tags",
+            Backend::Azure => "openai/models",
+            _ => "models",
+        };
+        let mut url = format!("{}/{}", self.config.api_base_url, path);
+        if self.config.backend == Backend::Azure {
+            url.push_str(&format!("?api-version={}", self.config.azure_api_version));
+        }
+        
+        let response = self
+            .authorize(self.client.get(&url))
+            .timeout(Duration::from_secs(self.config.request_timeout_secs))
             .send()
             .await
-            .map_err(|e| format!("Request failed: {}", e))?;
+            .map_err(|e| self.request_error("Request failed", e))?;
         
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(format!("this backend does not expose /{}", path));
+        }
         if !response.status().is_success() {
             let status = response.status();
             let body = response.text().await.unwrap_or_default();
             return Err(format!("API error {}: {}", status, body));
         }
         
-        let chat_response: ChatResponse = response
+        if self.config.backend == Backend::Ollama {
+            let tags: OllamaTags = response
+                .json()
+                .await
+                .map_err(|e| format!("Parse error: {}", e))?;
+            return Ok(tags.models.into_iter().map(|m| m.name).collect());
+        }
+        
+        let list: ModelList = response
             .json()
             .await
             .map_err(|e| format!("Parse error: {}", e))?;
+        Ok(list.data.into_iter().map(|m| m.id).collect())
+    }
+    
+    /// Describe a failed request, reporting timeouts distinctly
+    fn request_error(&self, context: &str, e: reqwest::Error) -> String {
+        if e.is_timeout() {
+            format!("Request timed out after {}s", self.config.request_timeout_secs)
+        } else {
+            format!("{}: {}", context, e)
+        }
+    }
+    
+    /// Add the API key, if any, in the backend's auth header, and the operator's extra headers
+    fn authorize(&self, mut req: RequestBuilder) -> RequestBuilder {
+        for (name, value) in &self.config.extra_headers {
+            req = req.header(name, value);
+        }
+        match (self.config.backend, &self.config.api_key) {
+            (Backend::OpenAiCompatible | Backend::Ollama, Some(api_key)) => req.header("Authorization", format!("Bearer {}", api_key)),
+            (Backend::OpenAiCompatible | Backend::Ollama, None) => req,
+            (Backend::Azure, Some(api_key)) => req.header("api-key", api_key),
+            (Backend::Azure, None) => req,
+            (Backend::Anthropic, api_key) => {
+                let req = req.header("anthropic-version", ANTHROPIC_VERSION);
+                match api_key {
+                    Some(api_key) => req.header("x-api-key", api_key),
+                    None => req,
+                }
+            }
+        }
+    }
+    
+    /// Post a chat request and check the response status, failing over to the next
+    /// endpoint and retrying transient failures with exponential backoff
+    async fn send(&self, params: &GenerationParams, messages: &[Message], stream: bool) -> Result<reqwest::Response, String> {
+        let mut attempt = 0;
+        loop {
+            match self.send_to_endpoints(params, messages, stream).await {
+                Ok(response) => return Ok(response),
+                Err(e) if e.transient && attempt < self.config.max_retries => {
+                    let delay = e.retry_after.unwrap_or_else(|| retry_delay(attempt));
+                    warn!("LLM request failed ({}), retrying in {:?}", e.message, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.message),
+            }
+        }
+    }
+    
+    /// Try each endpoint in turn until one answers, returning the last failure
+    async fn send_to_endpoints(&self, params: &GenerationParams, messages: &[Message], stream: bool) -> Result<reqwest::Response, SendError> {
+        let mut endpoints = self.config.endpoints().peekable();
+        loop {
+            let endpoint = endpoints.next().unwrap_or(&self.config.api_base_url);
+            match self.send_once(endpoint, params, messages, stream).await {
+                Ok(response) => {
+                    if !self.config.fallback_endpoints.is_empty() {
+                        info!("LLM request served by {}", endpoint);
+                    }
+                    return Ok(response);
+                }
+                Err(e) if e.failover && endpoints.peek().is_some() => {
+                    warn!("LLM endpoint {} failed ({}), trying the next one", endpoint, e.message);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    
+    /// Chat request URL on `endpoint`; Azure deployments default to the model name
+    fn chat_url(&self, endpoint: &str, params: &GenerationParams) -> String {
+        match self.config.backend {
+            Backend::Azure => format!(
+                "{}/openai/deployments/{}{}?api-version={}",
+                endpoint,
+                self.config.azure_deployment.as_deref().unwrap_or(&params.model),
+                Backend::Azure.chat_path(),
+                self.config.azure_api_version
+            ),
+            backend => format!("{}{}", endpoint, backend.chat_path()),
+        }
+    }
+    
+    /// Make one attempt at a chat request
+    async fn send_once(&self, endpoint: &str, params: &GenerationParams, messages: &[Message], stream: bool) -> Result<reqwest::Response, SendError> {
+        let url = self.chat_url(endpoint, params);
+        
+        let request = self.request_body(params, messages, stream);
+        
+        let req = self.client.post(&url)
+            .header("Content-Type", "application/json");
         
-        chat_response
-            .choices
-            .first()
-            .map(|c| c.message.content.clone())
-            .ok_or_else(|| "No response from LLM".to_string())
+        let mut req = self.authorize(req).json(&request);
+        if !stream {
+            req = req.timeout(Duration::from_secs(self.config.request_timeout_secs));
+        }
+        
+        let response = req.send().await.map_err(|e| SendError {
+            // A backend that already timed out is unlikely to answer faster next time
+            transient: !e.is_timeout(),
+            failover: true,
+            message: self.request_error("Request failed", e),
+            retry_after: None,
+        })?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .map(|secs| Duration::from_secs(secs.min(self.config.request_timeout_secs)));
+            let body = response.text().await.unwrap_or_default();
+            return Err(SendError {
+                message: format!("API error {}: {}", status, body),
+                transient: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                failover: status.is_server_error(),
+                retry_after: retry_after.filter(|_| status == StatusCode::TOO_MANY_REQUESTS),
+            });
+        }
+        
+        Ok(response)
+    }
+    
+    /// Perform a single non-streaming completion request
+    async fn complete(
+        &self,
+        params: &GenerationParams,
+        messages: &[Message],
+    ) -> Result<(ResponseMessage, Option<Usage>, Option<String>), String> {
+        let response = self.send(params, messages, false).await?;
+        
+        match self.config.backend {
+            Backend::OpenAiCompatible | Backend::Azure => {
+                let chat_response: ChatResponse = response
+                    .json()
+                    .await
+                    .map_err(|e| self.request_error("Parse error", e))?;
+                let message = chat_response
+                    .choices
+                    .into_iter()
+                    .next()
+                    .map(|c| c.message)
+                    .ok_or_else(|| "No response from LLM".to_string())?;
+                Ok((message, chat_response.usage, chat_response.system_fingerprint))
+            }
+            Backend::Anthropic => {
+                let response: AnthropicResponse = response
+                    .json()
+                    .await
+                    .map_err(|e| self.request_error("Parse error", e))?;
+                let thinking: Vec<&str> = response.content.iter().filter_map(|b| b.thinking.as_deref()).collect();
+                let message = ResponseMessage {
+                    content: response.content.iter().find(|b| b.kind == "text").and_then(|b| b.text.clone()),
+                    reasoning_content: Some(thinking.join("\n\n")),
+                    tool_calls: None,
+                };
+                Ok((message, response.usage.map(Usage::from), None))
+            }
+            Backend::Ollama => {
+                let chunk: OllamaChunk = response
+                    .json()
+                    .await
+                    .map_err(|e| self.request_error("Parse error", e))?;
+                if let Some(error) = chunk.error {
+                    return Err(format!("API error: {}", error));
+                }
+                let usage = chunk.usage();
+                let message = chunk.message.ok_or_else(|| "No response from LLM".to_string())?;
+                let message = ResponseMessage {
+                    content: message.content,
+                    reasoning_content: message.thinking,
+                    tool_calls: None,
+                };
+                Ok((message, usage, None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{config, json_response, openai_answer, stream_once, MockBackend, SilentBackend};
+    
+    fn client(config: Config) -> LlmClient {
+        LlmClient::new(Arc::new(config), None).unwrap()
+    }
+    
+    #[test]
+    fn retry_delay_doubles_up_to_the_maximum() {
+        assert_eq!(retry_delay(0), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2), RETRY_BASE_DELAY * 4);
+        assert_eq!(retry_delay(31), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+    
+    #[tokio::test]
+    async fn transient_errors_are_retried_until_the_backend_answers() {
+        let backend = MockBackend::start(vec![
+            json_response("503 Service Unavailable", r#"{"error":"busy"}"#),
+            json_response("429 Too Many Requests", r#"{"error":"slow down"}"#),
+            openai_answer("hello"),
+        ])
+        .await;
+        let llm = client(Config { max_retries: 2, ..config(&backend.url) });
+        
+        let outcome = llm.chat(&GenerationParams::default(), vec![Message::new("user", "hi")]).await.unwrap();
+        assert_eq!(outcome.content, "hello");
+        assert_eq!(backend.requests(), 3);
+    }
+    
+    #[tokio::test]
+    async fn retries_stop_after_max_retries() {
+        let backend = MockBackend::start(vec![json_response("503 Service Unavailable", "{}")]).await;
+        let llm = client(Config { max_retries: 1, ..config(&backend.url) });
+        
+        let error = llm.chat(&GenerationParams::default(), vec![Message::new("user", "hi")]).await.unwrap_err();
+        assert!(error.contains("503"), "{}", error);
+        assert_eq!(backend.requests(), 2);
+    }
+    
+    #[tokio::test]
+    async fn ollama_stream_is_read_across_chunk_boundaries_until_done() {
+        let url = stream_once(vec![
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n{\"message\":{\"role\":\"assis",
+            "tant\",\"content\":\"lo, wörld\"},\"done\":false}\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"prompt_eval_count\":7,\"eval_count\":3}\n",
+            // Anything after the final chunk is ignored
+            "{\"message\":{\"content\":\"extra\"},\"done\":false}\n",
+        ])
+        .await;
+        let llm = client(Config { backend: Backend::Ollama, ..config(&url) });
+        
+        let mut deltas = Vec::new();
+        let outcome = llm
+            .chat_stream(&GenerationParams::default(), vec![Message::new("user", "hi")], |event| {
+                if let StreamEvent::Content(delta) = event {
+                    deltas.push(delta);
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(deltas, ["Hel", "lo, wörld"]);
+        assert_eq!(outcome.content, "Hello, wörld");
+        let usage = outcome.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (7, 3));
+    }
+    
+    #[test]
+    fn azure_chat_url_names_the_deployment_and_api_version() {
+        let params = GenerationParams { model: "gpt-4o".to_string(), ..Default::default() };
+        let endpoint = "https://res.openai.azure.com";
+        
+        let llm = client(Config { backend: Backend::Azure, ..config(endpoint) });
+        assert_eq!(
+            llm.chat_url(endpoint, &params),
+            "https://res.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21"
+        );
+        
+        let llm = client(Config {
+            backend: Backend::Azure,
+            azure_deployment: Some("prod-chat".to_string()),
+            azure_api_version: "2025-01-01-preview".to_string(),
+            ..config(endpoint)
+        });
+        assert_eq!(
+            llm.chat_url(endpoint, &params),
+            "https://res.openai.azure.com/openai/deployments/prod-chat/chat/completions?api-version=2025-01-01-preview"
+        );
+    }
+    
+    fn cached_client(config: Config) -> LlmClient {
+        LlmClient::new(Arc::new(config), Some(Arc::new(ResponseCache::new(8)))).unwrap()
+    }
+    
+    fn greedy(model: &str) -> GenerationParams {
+        GenerationParams { model: model.to_string(), temperature: Some(0.0), use_cache: true, ..Default::default() }
+    }
+    
+    #[tokio::test]
+    async fn cache_hit_skips_the_backend() {
+        let backend = MockBackend::start(vec![openai_answer("cached")]).await;
+        let llm = cached_client(config(&backend.url));
+        let messages = vec![Message::new("user", "hi")];
+        
+        llm.chat(&greedy("m"), messages.clone()).await.unwrap();
+        let outcome = llm.chat(&greedy("m"), messages).await.unwrap();
+        assert_eq!(outcome.content, "cached");
+        assert_eq!(backend.requests(), 1);
+    }
+    
+    #[test]
+    fn cache_key_covers_the_whole_request() {
+        let llm = cached_client(config("http://localhost"));
+        let hi = [Message::new("user", "hi")];
+        let key = llm.cache_key(&greedy("m"), &hi).unwrap();
+        
+        assert_eq!(llm.cache_key(&greedy("m"), &hi), Some(key));
+        assert_ne!(llm.cache_key(&greedy("m"), &[Message::new("user", "hello")]), Some(key));
+        assert_ne!(llm.cache_key(&greedy("other"), &hi), Some(key));
+        assert_ne!(llm.cache_key(&GenerationParams { max_tokens: Some(10), ..greedy("m") }, &hi), Some(key));
+    }
+    
+    #[test]
+    fn only_greedy_requests_are_cached() {
+        let llm = cached_client(config("http://localhost"));
+        let hi = [Message::new("user", "hi")];
+        
+        assert!(llm.cache_key(&GenerationParams { temperature: Some(0.7), ..greedy("m") }, &hi).is_none());
+        assert!(llm.cache_key(&GenerationParams { temperature: None, ..greedy("m") }, &hi).is_none());
+        assert!(llm.cache_key(&GenerationParams { use_cache: false, ..greedy("m") }, &hi).is_none());
+        assert!(client(config("http://localhost")).cache_key(&greedy("m"), &hi).is_none());
+    }
+    
+    #[tokio::test]
+    async fn unanswered_requests_time_out() {
+        let backend = SilentBackend::start().await;
+        let llm = client(Config { request_timeout_secs: 1, max_retries: 2, ..config(&backend.url) });
+        let started = std::time::Instant::now();
+        
+        let error = llm.chat(&GenerationParams::default(), vec![Message::new("user", "hi")]).await.unwrap_err();
+        assert_eq!(error, "Request timed out after 1s");
+        assert!(started.elapsed() < Duration::from_secs(3), "{:?}", started.elapsed());
+        // A backend that timed out isn't asked again
+        assert_eq!(backend.requests(), 1);
+        
+        let error = llm
+            .chat_stream(&GenerationParams::default(), vec![Message::new("user", "hi")], |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(error, "Request timed out after 1s");
     }
 }
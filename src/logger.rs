@@ -1,25 +1,282 @@
-use chrono::{Local, Utc};
+use chrono::{DateTime, Days, Local, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::limiter::DailyCounts;
+#[cfg(feature = "sqlite")]
+use crate::sqlite_log::LogDatabase;
 
 #[derive(Default, Clone)]
 pub struct UserSummary {
     pub name: Option<String>,
     pub total_sessions: u32,
+    /// Messages the user has sent across all sessions
+    pub total_messages: u64,
+    pub first_seen: Option<DateTime<Utc>>,
+    /// Time of the last write before this session, i.e. the previous visit
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Preferred response mode; `None` means the server default
+    pub stream: Option<bool>,
+    /// Language code pinned with `/lang`
+    pub language: Option<String>,
+    /// Messages sent on `messages_day` (local date), for the daily limit
+    pub messages_today: u32,
+    pub messages_day: Option<NaiveDate>,
+}
+
+impl UserSummary {
+    /// Parse the `key: value` lines of `summary.txt`
+    fn parse(text: &str) -> Self {
+        let mut summary = Self::default();
+        for line in text.lines() {
+            if let Some((key, value)) = line.split_once(':') {
+                let key = key.trim().to_lowercase();
+                let value = value.trim();
+                match key.as_str() {
+                    "name" => summary.name = Some(value.to_string()),
+                    "total_sessions" => {
+                        summary.total_sessions = value.parse().unwrap_or(0);
+                    }
+                    "total_messages" => {
+                        summary.total_messages = value.parse().unwrap_or(0);
+                    }
+                    "first_seen" => summary.first_seen = value.parse().ok(),
+                    "last_seen" => summary.last_seen = value.parse().ok(),
+                    "stream" => summary.stream = Some(value == "on"),
+                    "language" => summary.language = Some(value.to_string()),
+                    "messages_today" => {
+                        summary.messages_today = value.parse().unwrap_or(0);
+                    }
+                    "messages_day" => summary.messages_day = value.parse().ok(),
+                    _ => {}
+                }
+            }
+        }
+        summary
+    }
+
+    /// The summary as `summary.txt` stores it, and as `/mydata` shows it
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        if let Some(ref name) = self.name {
+            text.push_str(&format!("name: {}\n", name));
+        }
+        text.push_str(&format!("total_sessions: {}\n", self.total_sessions));
+        text.push_str(&format!("total_messages: {}\n", self.total_messages));
+        if let Some(first_seen) = self.first_seen {
+            text.push_str(&format!("first_seen: {}\n", first_seen.to_rfc3339()));
+        }
+        if let Some(stream) = self.stream {
+            text.push_str(&format!("stream: {}\n", if stream { "on" } else { "off" }));
+        }
+        if let Some(ref language) = self.language {
+            text.push_str(&format!("language: {}\n", language));
+        }
+        if let Some(day) = self.messages_day {
+            text.push_str(&format!("messages_today: {}\nmessages_day: {}\n", self.messages_today, day));
+        }
+        if let Some(last_seen) = self.last_seen {
+            text.push_str(&format!("last_seen: {}\n", last_seen.to_rfc3339()));
+        }
+        text
+    }
+    
+    /// Messages sent today, counting from zero again each local day
+    pub fn messages_sent_today(&self) -> u32 {
+        if self.messages_day == Some(Local::now().date_naive()) {
+            self.messages_today
+        } else {
+            0
+        }
+    }
+    
+    /// Count a message towards today's `limit`; false, and nothing counted, once it is reached
+    pub fn take_daily_message(&mut self, limit: u32) -> bool {
+        let sent = self.messages_sent_today();
+        if sent >= limit {
+            return false;
+        }
+        self.messages_today = sent + 1;
+        self.messages_day = Some(Local::now().date_naive());
+        true
+    }
+}
+
+/// One chat log line, stored as a JSON object so content can hold any text
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    time: String,
+    /// Id of the session that wrote the entry; missing in older logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+    role: String,
+    content: String,
+}
+
+/// A logged message matching a `/search` query
+pub struct SearchHit {
+    /// `YYYY-MM-DD HH:MM` in local time
+    pub time: String,
+    pub role: String,
+    pub content: String,
+}
+
+/// `YYYY-MM-DD HH:MM` for a logged time: RFC 3339, or `HH:MM:SS` from an older log of `date`
+fn display_time(time: &str, date: Option<NaiveDate>) -> String {
+    match DateTime::parse_from_rfc3339(time) {
+        Ok(t) => t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string(),
+        Err(_) => {
+            let date = date.map(|d| d.format("%Y-%m-%d ").to_string()).unwrap_or_default();
+            format!("{}{}", date, time.get(..5).unwrap_or(time))
+        }
+    }
+}
+
+/// Longest directory name an identity maps to
+const MAX_DIR_NAME_LEN: usize = 128;
+
+/// Directory name for an identity (`key_<hex>` or an IP address), safe to join onto the logs dir
+fn identity_dir_name(identity: &str) -> String {
+    let name: String = identity
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .take(MAX_DIR_NAME_LEN)
+        .collect();
+    
+    // Never resolve to the logs dir itself or its parent
+    if name.chars().all(|c| c == '.') {
+        "unknown".to_string()
+    } else {
+        name
+    }
+}
+
+/// Date of a chat log file name: `chat_YYYY-MM-DD.log`, or a rotated `chat_YYYY-MM-DD.log.N`
+fn chat_log_date(name: &str) -> Option<NaiveDate> {
+    let rest = name.strip_prefix("chat_")?;
+    let (date, suffix) = rest.split_once(".log")?;
+    if !suffix.is_empty() && suffix.strip_prefix('.').is_none_or(|n| n.parse::<u32>().is_err()) {
+        return None;
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// Whether `label` is usable as a snapshot file name: 1-64 letters, digits, `-` or `_`
+pub fn is_valid_snapshot_label(label: &str) -> bool {
+    (1..=64).contains(&label.len()) && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Parse a chat log line; session markers, unreadable lines and lines from before the JSON format
+/// (`[HH:MM:SS] role: content`, which can't be split reliably) give `None`
+fn parse_log_line(line: &str) -> Option<LogEntry> {
+    serde_json::from_str(line).ok()
+}
+
+/// Name of the event a marker line records, e.g. `session_start`
+fn parse_event_line(line: &str) -> Option<String> {
+    let marker: serde_json::Value = serde_json::from_str(line).ok()?;
+    marker.get("event")?.as_str().map(str::to_string)
+}
+
+/// Current local time for log entries
+fn log_time() -> String {
+    Local::now().to_rfc3339_opts(SecondsFormat::Secs, false)
+}
+
+/// Which storage chat logs and user summaries go to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogBackend {
+    /// A directory per user with `summary.txt` and daily `chat_*.log` files
+    #[default]
+    Files,
+    /// One `sshllm.db` SQLite database in the logs dir (needs the `sqlite` feature)
+    Sqlite,
+}
+
+/// Storage shared by every session's logger
+#[derive(Default)]
+pub struct LogStore {
+    /// One lock per user directory, so sessions sharing an identity update `summary.txt` in turn
+    summary_locks: Mutex<HashMap<PathBuf, Weak<Mutex<()>>>>,
+    /// Daily message counts of sessions that store nothing
+    unstored_counts: Arc<DailyCounts>,
+    #[cfg(feature = "sqlite")]
+    database: Option<Arc<LogDatabase>>,
+}
+
+impl LogStore {
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            database: Some(Arc::new(LogDatabase::open(path)?)),
+            ..Self::default()
+        })
+    }
+
+    fn lock_for(&self, dir: &Path) -> Arc<Mutex<()>> {
+        let mut locks = self.summary_locks.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(lock) = locks.get(dir).and_then(Weak::upgrade) {
+            return lock;
+        }
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        let lock = Arc::new(Mutex::new(()));
+        locks.insert(dir.to_path_buf(), Arc::downgrade(&lock));
+        lock
+    }
 }
 
 pub struct ClientLogger {
+    logs_dir: PathBuf,
     base_dir: PathBuf,
+    summary_lock: Arc<Mutex<()>>,
+    /// Size at which today's log is rotated; 0 disables rotation
+    max_log_bytes: u64,
+    /// Id written with every entry, so one session's turns can be told apart from the rest of the day
+    session_id: Option<String>,
+    identity: String,
+    unstored_counts: Arc<DailyCounts>,
+    /// Set when logging to SQLite instead of files
+    #[cfg(feature = "sqlite")]
+    database: Option<Arc<LogDatabase>>,
 }
 
 impl ClientLogger {
-    pub fn new(logs_dir: &PathBuf, identity: String) -> Self {
-        let base_dir = logs_dir.join(identity);
-        Self { base_dir }
+    /// Logger for an identity; key users keep the same directory whatever IP they connect from
+    pub fn new(logs_dir: &Path, identity: &str, store: &LogStore) -> Self {
+        let base_dir = logs_dir.join(identity_dir_name(identity));
+        let summary_lock = store.lock_for(&base_dir);
+        Self {
+            logs_dir: logs_dir.to_path_buf(),
+            base_dir,
+            summary_lock,
+            max_log_bytes: 0,
+            session_id: None,
+            identity: identity.to_string(),
+            unstored_counts: store.unstored_counts.clone(),
+            #[cfg(feature = "sqlite")]
+            database: store.database.clone(),
+        }
+    }
+
+    pub fn with_max_log_bytes(mut self, max_log_bytes: u64) -> Self {
+        self.max_log_bytes = max_log_bytes;
+        self
+    }
+
+    pub fn with_session_id(mut self, session_id: String) -> Self {
+        self.session_id = Some(session_id);
+        self
     }
 
     pub fn init(&self) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if self.database.is_some() {
+            return Ok(());
+        }
         fs::create_dir_all(&self.base_dir)?;
         Ok(())
     }
@@ -28,130 +285,561 @@ impl ClientLogger {
         self.base_dir.join("summary.txt")
     }
 
+    fn snapshots_dir(&self) -> PathBuf {
+        self.base_dir.join("snapshots")
+    }
+
     fn chat_log_path(&self) -> PathBuf {
         let date = Local::now().format("%Y-%m-%d").to_string();
         self.base_dir.join(format!("chat_{}.log", date))
     }
 
-    pub fn update_session_start(&self) -> std::io::Result<UserSummary> {
-        let path = self.summary_path();
-        let mut summary = UserSummary::default();
-
-        // Read existing summary
-        if path.exists() {
-            let file = File::open(&path)?;
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let line = line?;
-                if let Some((key, value)) = line.split_once(':') {
-                    let key = key.trim().to_lowercase();
-                    let value = value.trim();
-                    match key.as_str() {
-                        "name" => summary.name = Some(value.to_string()),
-                        "total_sessions" => {
-                            summary.total_sessions = value.parse().unwrap_or(0);
-                        }
-                        _ => {}
-                    }
-                }
-            }
+    fn load_summary(&self) -> std::io::Result<UserSummary> {
+        match fs::read_to_string(self.summary_path()) {
+            Ok(text) => Ok(UserSummary::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(UserSummary::default()),
+            Err(e) => Err(e),
         }
+    }
 
-        // Increment session count
-        summary.total_sessions += 1;
+    pub fn update_session_start(&self) -> std::io::Result<UserSummary> {
+        self.update_summary(|summary| {
+            summary.total_sessions += 1;
+            summary.first_seen.get_or_insert_with(Utc::now);
+        })
+    }
 
-        // Write updated summary
+    /// Apply a change to the stored summary and write it back
+    pub fn update_summary(&self, change: impl FnOnce(&mut UserSummary)) -> std::io::Result<UserSummary> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.update_summary(&self.identity, change);
+        }
+        let _guard = self.summary_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut summary = self.load_summary()?;
+        change(&mut summary);
         self.write_summary(&summary)?;
-
         Ok(summary)
     }
 
+    /// Write the summary to a temporary file and rename it over `summary.txt`, so readers never see a partial file
     fn write_summary(&self, summary: &UserSummary) -> std::io::Result<()> {
-        let path = self.summary_path();
-        let mut file = File::create(&path)?;
+        let tmp_path = self.base_dir.join(format!(".summary.txt.{}.tmp", std::process::id()));
+        let mut file = File::create(&tmp_path)?;
         
-        if let Some(ref name) = summary.name {
-            writeln!(file, "name: {}", name)?;
-        }
-        writeln!(file, "total_sessions: {}", summary.total_sessions)?;
-        writeln!(file, "last_seen: {}", Utc::now().to_rfc3339())?;
+        let stored = UserSummary { last_seen: Some(Utc::now()), ..summary.clone() };
+        file.write_all(stored.render().as_bytes())?;
+        file.sync_all()?;
+        drop(file);
         
-        Ok(())
+        fs::rename(&tmp_path, self.summary_path())
+    }
+
+    /// The stored summary; a default one if nothing is stored
+    pub fn stored_summary(&self) -> std::io::Result<UserSummary> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return Ok(db.read_summary(&self.identity)?.unwrap_or_default());
+        }
+        let _guard = self.summary_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.load_summary()
+    }
+
+    /// Count a message towards today's `limit` in the stored summary, re-read under the
+    /// summary lock so every session of the identity shares one count
+    pub fn take_daily_message(&self, limit: u32) -> std::io::Result<(bool, UserSummary)> {
+        let mut allowed = false;
+        let summary = self.update_summary(|summary| allowed = summary.take_daily_message(limit))?;
+        Ok((allowed, summary))
+    }
+
+    /// Like `take_daily_message`, counted in memory for sessions that store nothing
+    pub fn take_unstored_daily_message(&self, limit: u32) -> bool {
+        self.unstored_counts.take(&self.identity, limit)
     }
 
     pub fn set_user_name(&self, name: &str) -> std::io::Result<()> {
-        let path = self.summary_path();
-        let mut summary = UserSummary::default();
+        self.update_summary(|summary| summary.name = Some(name.to_string()))?;
+        Ok(())
+    }
+
+    /// Raw contents of the stored summary, if any
+    pub fn read_summary(&self) -> std::io::Result<Option<String>> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return Ok(db.read_summary(&self.identity)?.map(|summary| summary.render()));
+        }
+        match fs::read_to_string(self.summary_path()) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Names of the stored chat log files (days, for SQLite), oldest first
+    pub fn list_chat_logs(&self) -> Vec<String> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.log_days(&self.identity).unwrap_or_default();
+        }
+        let mut logs: Vec<String> = fs::read_dir(&self.base_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| chat_log_date(name).is_some())
+            .collect();
+        logs.sort();
+        logs
+    }
+
+    pub fn delete_summary(&self) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.delete_summary(&self.identity);
+        }
+        match fs::remove_file(self.summary_path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Delete today's chat log, and its rotations, so it isn't reloaded on the next connection
+    pub fn clear_today(&self) -> std::io::Result<()> {
+        let today = Local::now().date_naive();
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.delete_day(&self.identity, today).map(|_| ());
+        }
+        for name in self.list_chat_logs() {
+            if chat_log_date(&name) == Some(today) {
+                fs::remove_file(self.base_dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete_chat_logs(&self) -> std::io::Result<usize> {
+        let logs = self.list_chat_logs();
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            db.delete_messages(&self.identity)?;
+            return Ok(logs.len());
+        }
+        for name in &logs {
+            fs::remove_file(self.base_dir.join(name))?;
+        }
+        Ok(logs.len())
+    }
+
+    /// Store a saved conversation under `label`, replacing one saved under the same label
+    pub fn save_snapshot(&self, label: &str, data: &str) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.save_snapshot(&self.identity, label, data);
+        }
+        let dir = self.snapshots_dir();
+        fs::create_dir_all(&dir)?;
+        let tmp_path = dir.join(format!(".{}.json.{}.tmp", label, std::process::id()));
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, dir.join(format!("{}.json", label)))
+    }
+
+    /// The conversation saved under `label`, if there is one
+    pub fn load_snapshot(&self, label: &str) -> std::io::Result<Option<String>> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.load_snapshot(&self.identity, label);
+        }
+        match fs::read_to_string(self.snapshots_dir().join(format!("{}.json", label))) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Labels of the saved conversations, alphabetically
+    pub fn list_snapshots(&self) -> Vec<String> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.snapshot_labels(&self.identity).unwrap_or_default();
+        }
+        let mut labels: Vec<String> = fs::read_dir(self.snapshots_dir())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(".json").map(str::to_string))
+            .filter(|label| is_valid_snapshot_label(label))
+            .collect();
+        labels.sort();
+        labels
+    }
+
+    pub fn delete_snapshots(&self) -> std::io::Result<usize> {
+        let labels = self.list_snapshots();
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.delete_snapshots(&self.identity);
+        }
+        for label in &labels {
+            fs::remove_file(self.snapshots_dir().join(format!("{}.json", label)))?;
+        }
+        Ok(labels.len())
+    }
+
+    /// Remove the user's whole directory, refusing anything that isn't a real directory directly inside the logs dir
+    pub fn purge(&self) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            db.delete_messages(&self.identity)?;
+            db.delete_snapshots(&self.identity)?;
+            return db.delete_summary(&self.identity);
+        }
+        let meta = match fs::symlink_metadata(&self.base_dir) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
         
-        if path.exists() {
-            let file = File::open(&path)?;
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let line = line?;
-                if let Some((key, value)) = line.split_once(':') {
-                    let key = key.trim().to_lowercase();
-                    let value = value.trim();
-                    if key == "total_sessions" {
-                        summary.total_sessions = value.parse().unwrap_or(0);
-                    }
-                }
+        let logs_dir = fs::canonicalize(&self.logs_dir)?;
+        let base_dir = fs::canonicalize(&self.base_dir)?;
+        if !meta.is_dir() || base_dir.parent() != Some(logs_dir.as_path()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("refusing to delete {} outside {}", self.base_dir.display(), self.logs_dir.display()),
+            ));
+        }
+        fs::remove_dir_all(&base_dir)
+    }
+
+    /// Delete chat logs, rotated ones included, dated more than `days` days ago; 0 keeps everything
+    pub fn remove_old_logs(&self, days: u32) -> std::io::Result<usize> {
+        if days == 0 {
+            return Ok(0);
+        }
+        let Some(cutoff) = Local::now().date_naive().checked_sub_days(Days::new(days.into())) else {
+            return Ok(0);
+        };
+        
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            let expired = db
+                .log_days(&self.identity)?
+                .iter()
+                .filter(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").is_ok_and(|date| date < cutoff))
+                .count();
+            db.delete_before(&self.identity, cutoff)?;
+            return Ok(expired);
+        }
+        
+        let mut removed = 0;
+        for name in self.list_chat_logs() {
+            if chat_log_date(&name).is_some_and(|date| date < cutoff) {
+                fs::remove_file(self.base_dir.join(name))?;
+                removed += 1;
             }
         }
+        Ok(removed)
+    }
+
+    /// Shift today's log to `.1` (and older rotations up by one) once it reaches the size limit
+    fn rotate_if_full(&self, path: &Path) -> std::io::Result<()> {
+        if self.max_log_bytes == 0 {
+            return Ok(());
+        }
+        match fs::metadata(path) {
+            Ok(meta) if meta.len() >= self.max_log_bytes => {}
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
         
-        summary.name = Some(name.to_string());
-        self.write_summary(&summary)
+        let rotated = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+        let mut last = 1;
+        while rotated(last).exists() {
+            last += 1;
+        }
+        for n in (1..last).rev() {
+            fs::rename(rotated(n), rotated(n + 1))?;
+        }
+        fs::rename(path, rotated(1))
     }
 
-    pub fn log_message(&self, role: &str, content: &str) -> std::io::Result<()> {
+    /// Today's log opened for appending, rotated first if it is full
+    fn open_chat_log(&self) -> std::io::Result<File> {
         let path = self.chat_log_path();
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&path)?;
+        self.rotate_if_full(&path)?;
+        OpenOptions::new().create(true).append(true).open(&path)
+    }
+
+    pub fn log_message(&self, role: &str, content: &str) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            db.log(&self.identity, self.session_id.as_deref(), &log_time(), role, content)?;
+            if role == "user" {
+                self.update_summary(|summary| summary.total_messages += 1)?;
+            }
+            return Ok(());
+        }
+        let mut file = self.open_chat_log()?;
         
-        let timestamp = Local::now().format("%H:%M:%S");
-        writeln!(file, "[{}] {}: {}", timestamp, role, content)?;
+        let entry = LogEntry {
+            time: log_time(),
+            session: self.session_id.clone(),
+            role: role.to_string(),
+            content: content.to_string(),
+        };
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
         
+        if role == "user" {
+            self.update_summary(|summary| summary.total_messages += 1)?;
+        }
         Ok(())
     }
 
     pub fn log_session_start(&self) -> std::io::Result<()> {
-        let path = self.chat_log_path();
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&path)?;
+        self.log_event("session_start")
+    }
+
+    /// Write a `session_end` marker and sync today's log, for sessions closed at shutdown
+    pub fn log_session_end(&self) -> std::io::Result<()> {
+        self.log_event("session_end")?;
+        #[cfg(feature = "sqlite")]
+        if self.database.is_some() {
+            return Ok(());
+        }
+        File::open(self.chat_log_path())?.sync_all()
+    }
+
+    /// Write a marker such as `session_start` or `undo`; history reloads see it as an `event` entry
+    pub fn log_event(&self, event: &str) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.log(&self.identity, self.session_id.as_deref(), &log_time(), "event", event);
+        }
+        let mut file = self.open_chat_log()?;
         
-        let timestamp = Local::now().format("%d-%m-%Y %H:%M:%S");
-        writeln!(file, "\n--- Session started at {} ---\n", timestamp)?;
+        let mut marker = serde_json::json!({ "time": log_time(), "event": event });
+        if let Some(ref id) = self.session_id {
+            marker["session"] = id.as_str().into();
+        }
+        writeln!(file, "{}", marker)?;
         
         Ok(())
     }
 
-    pub fn load_today_history(&self) -> Vec<(String, String)> {
+    /// The `limit` most recent messages, across all logs, containing `query` (ignoring case)
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        let query = query.to_lowercase();
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db
+                .search(&self.identity, &query, limit)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(time, role, content)| SearchHit { time: display_time(&time, None), role, content })
+                .collect();
+        }
+        
+        let mut hits = Vec::new();
+        // Newest day first; within a day the current file is newer than its rotations
+        let mut logs = self.list_chat_logs();
+        logs.sort_by(|a, b| chat_log_date(b).cmp(&chat_log_date(a)).then(a.len().cmp(&b.len())).then(a.cmp(b)));
+        for name in logs {
+            let Ok(file) = File::open(self.base_dir.join(&name)) else {
+                continue;
+            };
+            let date = chat_log_date(&name);
+            let mut matches: Vec<SearchHit> = BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| parse_log_line(&line))
+                .filter(|entry| entry.content.to_lowercase().contains(&query))
+                .map(|entry| SearchHit { time: display_time(&entry.time, date), role: entry.role, content: entry.content })
+                .collect();
+            matches.reverse();
+            hits.extend(matches);
+            if hits.len() >= limit {
+                break;
+            }
+        }
+        hits.truncate(limit);
+        hits
+    }
+
+    /// The last `limit` entries of today's log as (role, content)
+    pub fn load_today_history(&self, limit: usize) -> Vec<(String, String)> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.database {
+            return db.history(&self.identity, Local::now().date_naive(), limit).unwrap_or_default();
+        }
         let path = self.chat_log_path();
         let mut history = Vec::new();
         
         if let Ok(file) = File::open(&path) {
             let reader = BufReader::new(file);
-            for line in reader.lines().flatten() {
-                // Parse format: [HH:MM:SS] role: content
-                if let Some(rest) = line.strip_prefix('[') {
-                    if let Some(idx) = rest.find(']') {
-                        let after_time = &rest[idx + 1..].trim();
-                        if let Some((role, content)) = after_time.split_once(':') {
-                            history.push((role.trim().to_string(), content.trim().to_string()));
-                        }
-                    }
+            for line in reader.lines().map_while(Result::ok) {
+                if let Some(entry) = parse_log_line(&line) {
+                    history.push((entry.role, entry.content));
+                } else if let Some(event) = parse_event_line(&line).filter(|event| event == "undo") {
+                    // Session markers aren't needed to rebuild the conversation
+                    history.push(("event".to_string(), event));
                 }
             }
         }
         
         // Limit history
-        if history.len() > 20 {
-            history = history.split_off(history.len() - 20);
+        if history.len() > limit {
+            history = history.split_off(history.len() - limit);
         }
         
         history
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn logger(dir: &TempDir, identity: &str) -> ClientLogger {
+        let logger = ClientLogger::new(dir.path(), identity, &LogStore::default());
+        logger.init().unwrap();
+        logger
+    }
+
+    #[test]
+    fn summary_round_trips_through_its_text_form() {
+        let summary = UserSummary {
+            name: Some("Ada: the first".to_string()),
+            total_sessions: 3,
+            total_messages: 42,
+            first_seen: "2026-01-02T03:04:05Z".parse().ok(),
+            last_seen: "2026-02-03T04:05:06Z".parse().ok(),
+            stream: Some(false),
+            language: Some("pl".to_string()),
+            messages_today: 7,
+            messages_day: NaiveDate::from_ymd_opt(2026, 2, 3),
+        };
+        let parsed = UserSummary::parse(&summary.render());
+
+        assert_eq!(parsed.render(), summary.render());
+        assert_eq!(parsed.name.as_deref(), Some("Ada: the first"));
+        assert_eq!((parsed.total_sessions, parsed.total_messages), (3, 42));
+        assert_eq!(parsed.first_seen, summary.first_seen);
+        assert_eq!(parsed.last_seen, summary.last_seen);
+        assert_eq!(parsed.stream, Some(false));
+        assert_eq!(parsed.language.as_deref(), Some("pl"));
+        assert_eq!((parsed.messages_today, parsed.messages_day), (7, summary.messages_day));
+    }
+
+    #[test]
+    fn empty_summary_round_trips() {
+        let parsed = UserSummary::parse(&UserSummary::default().render());
+        assert_eq!(parsed.render(), UserSummary::default().render());
+        assert!(parsed.name.is_none() && parsed.stream.is_none() && parsed.messages_day.is_none());
+    }
+
+    #[test]
+    fn concurrent_summary_updates_are_not_lost() {
+        let dir = TempDir::new();
+        let store = LogStore::default();
+        logger(&dir, "key_a");
+
+        // One logger per session, as every connection of the identity gets its own
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                let logger = ClientLogger::new(dir.path(), "key_a", &store);
+                scope.spawn(move || {
+                    for _ in 0..25 {
+                        logger.update_summary(|summary| summary.total_messages += 1).unwrap();
+                    }
+                });
+            }
+        });
+
+        let summary = ClientLogger::new(dir.path(), "key_a", &store).stored_summary().unwrap();
+        assert_eq!(summary.total_messages, 200);
+    }
+
+    #[test]
+    fn retention_removes_only_expired_chat_logs() {
+        let dir = TempDir::new();
+        let logger = logger(&dir, "key_a");
+        let today = Local::now().date_naive();
+        let day = |days_ago: u64| today.checked_sub_days(Days::new(days_ago)).unwrap().format("%Y-%m-%d").to_string();
+        let names = [
+            format!("chat_{}.log", day(10)),
+            format!("chat_{}.log.1", day(10)),
+            format!("chat_{}.log", day(7)),
+            format!("chat_{}.log", day(6)),
+            format!("chat_{}.log", day(0)),
+            "summary.txt".to_string(),
+            "chat_notes.txt".to_string(),
+        ];
+        for name in &names {
+            fs::write(dir.path().join("key_a").join(name), "").unwrap();
+        }
+
+        assert_eq!(logger.remove_old_logs(0).unwrap(), 0);
+        assert_eq!(logger.remove_old_logs(7).unwrap(), 2);
+
+        let mut left: Vec<String> = fs::read_dir(dir.path().join("key_a"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        let mut expected = names[2..].to_vec();
+        expected.sort();
+        assert_eq!(left, expected);
+    }
+
+    #[test]
+    fn identity_dir_name_is_stable() {
+        assert_eq!(identity_dir_name("key_abc123DEF"), "key_abc123DEF");
+        assert_eq!(identity_dir_name("192.168.1.10"), "192.168.1.10");
+        assert_eq!(identity_dir_name("2001:db8::1"), identity_dir_name("2001:db8::1"));
+        assert_eq!(identity_dir_name("2001:db8::1"), "2001_db8__1");
+    }
+
+    #[test]
+    fn identity_dir_name_stays_inside_the_logs_dir() {
+        for identity in ["../etc", "a/../../b", "..\\x", "/abs/path", "c:\\windows", "x\0y"] {
+            let name = identity_dir_name(identity);
+            assert!(!name.contains(['/', '\\', ':', '\0']), "{:?} -> {:?}", identity, name);
+            assert_eq!(Path::new(&name).components().count(), 1, "{:?} -> {:?}", identity, name);
+        }
+        assert_eq!(identity_dir_name(".."), "unknown");
+        assert_eq!(identity_dir_name("."), "unknown");
+        assert_eq!(identity_dir_name(""), "unknown");
+        assert_eq!(identity_dir_name(&"k".repeat(500)).len(), MAX_DIR_NAME_LEN);
+    }
+
+    #[test]
+    fn multiline_content_round_trips() {
+        let dir = TempDir::new();
+        let logger = logger(&dir, "127.0.0.1");
+        let answer = "Steps:\n1. open http://example.com:8080/a?b=c\n2. done: really\n";
+        logger.log_message("user", "what: now?").unwrap();
+        logger.log_message("assistant", answer).unwrap();
+
+        let history = logger.load_today_history(10);
+        assert_eq!(
+            history,
+            vec![
+                ("user".to_string(), "what: now?".to_string()),
+                ("assistant".to_string(), answer.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_lines_are_skipped() {
+        let dir = TempDir::new();
+        let logger = logger(&dir, "127.0.0.1");
+        fs::write(logger.chat_log_path(), "[10:00:00] assistant: see http://example.com\n").unwrap();
+        logger.log_message("user", "hi").unwrap();
+
+        assert_eq!(logger.load_today_history(10), vec![("user".to_string(), "hi".to_string())]);
+    }
+}
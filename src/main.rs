@@ -1,42 +1,136 @@
+mod auth;
+mod cache;
 mod config;
 mod chat;
+mod editor;
+mod hooks;
+mod i18n;
+mod limiter;
 mod llm;
 mod logger;
+mod metrics;
+mod personas;
+mod proxy;
+mod rewrite;
 mod server;
+#[cfg(feature = "sqlite")]
+mod sqlite_log;
+mod stats;
+mod terminal;
+#[cfg(test)]
+mod test_support;
+mod tools;
+mod writer;
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use russh::server::Server as _;
 use russh::keys::{PrivateKey, Algorithm};
+use russh::keys::ssh_key::private::RsaKeypair;
 use russh::keys::ssh_key::LineEnding;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
-use tracing::info;
+use tracing::{info, warn};
 use russh::keys::signature::rand_core::OsRng;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::config::Config;
-use crate::server::SshServer;
+use crate::cache::ResponseCache;
+use crate::config::{Config, FileConfig};
+use crate::limiter::{ConnectionLimiter, RequestQueue};
+use crate::llm::{Backend, LlmClient};
+use crate::logger::{LogBackend, LogStore};
+use crate::server::{Clients, SshServer};
+use crate::stats::ServerStats;
+
+/// How the server's own log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with event fields such as `client` and `ip` as keys
+    Json,
+}
 
 /// SSH LLM Chat Server
 #[derive(Parser, Debug)]
 #[command(name = "sshllm")]
 #[command(about = "SSH server for LLM chat with OpenAI-compatible API")]
 struct Args {
+    /// TOML file with base settings; flags and environment variables override it
+    #[arg(short, long, env = "SSHLLM_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on: 0.0.0.0 for all IPv4, :: for IPv6 (and IPv4 where the OS allows), 127.0.0.1 for loopback only
+    #[arg(long, default_value = "0.0.0.0", env = "SSHLLM_BIND", value_parser = parse_bind_address)]
+    bind: IpAddr,
+
     /// Port to listen on
     #[arg(short, long, default_value = "2222", env = "SSHLLM_PORT")]
     port: u16,
 
+    /// Sampling temperature sent with each request (0.0-2.0)
+    #[arg(long, env = "SSHLLM_TEMPERATURE")]
+    temperature: Option<f64>,
+
+    /// Nucleus sampling top_p sent with each request (0.0-1.0)
+    #[arg(long, env = "SSHLLM_TOP_P")]
+    top_p: Option<f64>,
+
+    /// Maximum tokens the model may generate per response
+    #[arg(long, env = "SSHLLM_MAX_TOKENS")]
+    max_tokens: Option<u32>,
+
+    /// Sampling seed sent with each request, for reproducible output on backends that support it
+    #[arg(long, env = "SSHLLM_SEED")]
+    seed: Option<u64>,
+
     /// LLM API endpoint
     #[arg(short = 'e', long = "endpoint", env = "SSHLLM_API_URL")]
-    api_url: String,
+    api_url: Option<String>,
+
+    /// Endpoint tried when the ones before it can't be reached or fail with a 5xx (repeatable)
+    #[arg(long = "fallback-endpoint", env = "SSHLLM_FALLBACK_ENDPOINTS", value_delimiter = ',')]
+    fallback_endpoints: Vec<String>,
+
+    /// API format spoken by the endpoint
+    #[arg(long, value_enum, default_value_t = Backend::OpenAiCompatible, env = "SSHLLM_BACKEND")]
+    backend: Backend,
+
+    /// Azure OpenAI deployment name (azure backend; defaults to the model name)
+    #[arg(long, env = "SSHLLM_AZURE_DEPLOYMENT")]
+    azure_deployment: Option<String>,
+
+    /// Azure OpenAI `api-version` query parameter (azure backend)
+    #[arg(long, default_value = "2024-10-21", env = "SSHLLM_AZURE_API_VERSION")]
+    azure_api_version: String,
 
     /// LLM API key
     #[arg(short = 'a', long, env = "SSHLLM_API_KEY")]
     api_key: Option<String>,
 
+    /// Extra HTTP header sent with every LLM request, as KEY=VALUE (repeatable)
+    #[arg(long = "header", value_name = "KEY=VALUE", env = "SSHLLM_HEADERS", value_delimiter = ',')]
+    headers: Vec<String>,
+
+    /// Send LLM requests through this proxy (http://, https://, socks5:// or socks5h://);
+    /// overrides HTTPS_PROXY, HTTP_PROXY and ALL_PROXY
+    #[arg(long, env = "SSHLLM_PROXY")]
+    proxy: Option<String>,
+
+    /// Also trust the CA certificate(s) in this PEM or DER file for HTTPS endpoints (e.g. a self-signed server)
+    #[arg(long, env = "SSHLLM_CA_CERT")]
+    ca_cert: Option<PathBuf>,
+
+    /// Accept any TLS certificate from the LLM endpoint. Insecure: only for testing on a trusted network
+    #[arg(long, env = "SSHLLM_INSECURE_SKIP_VERIFY")]
+    insecure_skip_verify: bool,
+
     /// Model name
     #[arg(short, long, default_value = "default", env = "SSHLLM_MODEL")]
     model: String,
@@ -45,76 +139,740 @@ struct Args {
     #[arg(short, long, default_value = "logs", env = "SSHLLM_LOGS_DIR")]
     logs: PathBuf,
 
-    /// Path to SSH host key
-    #[arg(short = 'k', long, default_value = "keys/host_ed25519", env = "SSHLLM_HOST_KEY")]
-    host_key: PathBuf,
+    /// Format of the server's own log output on stderr
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, env = "SSHLLM_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Where chat logs and user summaries are stored
+    #[arg(long, value_enum, default_value_t = LogBackend::Files, env = "SSHLLM_LOG_BACKEND")]
+    log_backend: LogBackend,
+
+    /// Paths to SSH host keys (repeatable); missing keys are generated, as RSA if the file name contains "rsa"
+    #[arg(
+        short = 'k',
+        long = "host-key",
+        default_values = ["keys/host_ed25519", "keys/host_rsa"],
+        env = "SSHLLM_HOST_KEY",
+        value_delimiter = ','
+    )]
+    host_keys: Vec<PathBuf>,
 
     /// Custom system prompt
     #[arg(short, long, env = "SSHLLM_SYSTEM_PROMPT")]
     system_prompt: Option<String>,
+
+    /// Read the system prompt from a file (takes precedence over --system-prompt)
+    #[arg(long, env = "SSHLLM_SYSTEM_PROMPT_FILE")]
+    system_prompt_file: Option<PathBuf>,
+
+    /// Template wrapped around each user message sent to the LLM (use {input} as placeholder)
+    #[arg(long, env = "SSHLLM_INPUT_TEMPLATE")]
+    input_template: Option<String>,
+
+    /// Input prompt shown to users
+    #[arg(long, default_value = "You: ", env = "SSHLLM_PROMPT_STRING")]
+    prompt_string: String,
+
+    /// TOML file with localized welcome/prompt strings per language code
+    #[arg(long, env = "SSHLLM_TRANSLATIONS")]
+    translations: Option<PathBuf>,
+
+    /// TOML file with [[rule]] regex find/replace rules applied to responses
+    #[arg(long, env = "SSHLLM_REWRITE_RULES")]
+    rewrite_rules: Option<PathBuf>,
+
+    /// Directory of .txt/.md system prompts users can pick with /persona <name>
+    #[arg(long, env = "SSHLLM_PERSONAS_DIR")]
+    personas_dir: Option<PathBuf>,
+
+    /// Maximum size of a request body sent to the LLM; oldest history is trimmed to fit
+    #[arg(long, env = "SSHLLM_MAX_REQUEST_BYTES")]
+    max_request_bytes: Option<usize>,
+
+    /// Seconds to wait for the LLM backend before giving up on a request
+    #[arg(long = "timeout", default_value = "120", env = "SSHLLM_TIMEOUT")]
+    request_timeout_secs: u64,
+
+    /// Times to retry a request after a network error, 5xx or 429 response
+    #[arg(long, default_value = "2", env = "SSHLLM_MAX_RETRIES")]
+    max_retries: u32,
+
+    /// Don't trim history and retry when the backend reports a context-length error
+    #[arg(long, env = "SSHLLM_NO_CONTEXT_RETRY")]
+    no_context_retry: bool,
+
+    /// Wait for whole responses instead of streaming them (users can still opt in with /stream on)
+    #[arg(long, env = "SSHLLM_NO_STREAM")]
+    no_stream: bool,
+
+    /// Let the model call built-in tools (current time, calculator)
+    #[arg(long, env = "SSHLLM_TOOLS")]
+    tools: bool,
+
+    /// Pass escape sequences from the model through to the terminal unfiltered
+    #[arg(long, env = "SSHLLM_ALLOW_MODEL_ANSI")]
+    allow_model_ansi: bool,
+
+    /// Program to run when a user connects (receives identity and timestamp)
+    #[arg(long, env = "SSHLLM_ON_CONNECT_CMD")]
+    on_connect_cmd: Option<String>,
+
+    /// Program to run when a user disconnects (receives identity and timestamp)
+    #[arg(long, env = "SSHLLM_ON_DISCONNECT_CMD")]
+    on_disconnect_cmd: Option<String>,
+
+    /// Key fingerprints allowed to use operator commands (comma-separated)
+    #[arg(long = "admin-key", env = "SSHLLM_ADMIN_KEYS", value_delimiter = ',')]
+    admin_keys: Vec<String>,
+
+    /// Locked-down demo mode: nothing stored, fresh sessions, fixed prompt and model, data/operator commands disabled; the daily limit still applies
+    #[arg(long, env = "SSHLLM_KIOSK")]
+    kiosk: bool,
+
+    /// Log server-wide usage counters every N seconds (0 disables)
+    #[arg(long, default_value = "0", env = "SSHLLM_STATS_INTERVAL")]
+    stats_interval: u64,
+
+    /// Serve Prometheus metrics over HTTP at /metrics on this address (e.g. 127.0.0.1:9100)
+    #[arg(long, env = "SSHLLM_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// LLM requests in flight at once across all sessions; more wait in a queue (0 disables the limit)
+    #[arg(long, default_value = "4", env = "SSHLLM_MAX_CONCURRENT_LLM")]
+    max_concurrent_llm: usize,
+
+    /// Reuse answers to identical requests (same model, messages and settings) made with temperature 0
+    #[arg(long, env = "SSHLLM_CACHE")]
+    cache: bool,
+
+    /// Answers kept by --cache; the least recently used are dropped first
+    #[arg(long, default_value = "256", env = "SSHLLM_CACHE_SIZE")]
+    cache_size: usize,
+
+    /// Connections accepted from one IP address per minute (0 disables the limit)
+    #[arg(long, default_value = "0", env = "SSHLLM_MAX_CONNS_PER_IP")]
+    max_conns_per_ip: u32,
+
+    /// Concurrent sessions allowed server-wide (0 disables the limit)
+    #[arg(long, default_value = "0", env = "SSHLLM_MAX_SESSIONS")]
+    max_sessions: usize,
+
+    /// Disconnect sessions after this many seconds without input (0 disables)
+    #[arg(long = "idle-timeout", default_value = "600", env = "SSHLLM_IDLE_TIMEOUT")]
+    idle_timeout_secs: u64,
+
+    /// Keep a key user's session for N seconds after their connection drops, so reconnecting continues it (0 disables)
+    #[arg(long = "reattach-grace", default_value = "300", env = "SSHLLM_REATTACH_GRACE")]
+    reattach_grace_secs: u64,
+
+    /// Messages each user may send per day, counted in their stored summary (0 means unlimited)
+    #[arg(long, default_value = "0", env = "SSHLLM_DAILY_MESSAGE_LIMIT")]
+    daily_message_limit: u32,
+
+    /// Only accept public keys listed in this OpenSSH authorized_keys file (re-read on every login)
+    #[arg(long, env = "SSHLLM_AUTHORIZED_KEYS")]
+    authorized_keys: Option<PathBuf>,
+
+    /// Expect a PROXY protocol (v1 or v2) header on every connection and use the client address it carries;
+    /// only enable behind a load balancer that sends one, since clients could otherwise spoof their address
+    #[arg(long, env = "SSHLLM_TRUST_PROXY")]
+    trust_proxy: bool,
+
+    /// Only allow public key logins; password and guest access are refused
+    #[arg(long, alias = "no-anonymous", env = "SSHLLM_REQUIRE_KEY")]
+    require_key: bool,
+
+    /// Summarize the older half of a conversation once it holds this many messages (0 disables)
+    #[arg(long, default_value = "40", env = "SSHLLM_SUMMARIZE_AFTER")]
+    summarize_after: usize,
+
+    /// Most messages kept as context, rounded down to even; older ones are dropped
+    #[arg(long, default_value = "40", env = "SSHLLM_MAX_HISTORY_MESSAGES")]
+    max_history_messages: usize,
+
+    /// Entries of today's log reloaded as context when a user reconnects
+    #[arg(long, default_value = "20", env = "SSHLLM_MAX_LOADED_HISTORY")]
+    max_loaded_history: usize,
+
+    /// Rotate a day's chat log to `.1`, `.2`, ... once it reaches this many bytes (0 disables)
+    #[arg(long = "max-log-size", default_value = "0", env = "SSHLLM_MAX_LOG_SIZE")]
+    max_log_bytes: u64,
+
+    /// Delete chat logs older than this many days when their user connects (0 keeps them forever)
+    #[arg(long, default_value = "0", env = "SSHLLM_LOG_RETENTION_DAYS")]
+    log_retention_days: u32,
+
+    /// Model context window in tokens, used by /context to show how full it is
+    #[arg(long, default_value = "8192", env = "SSHLLM_CONTEXT_SIZE")]
+    context_size: usize,
+
+    /// Text file shown instead of the built-in ASCII art banner (re-read on every connection)
+    #[arg(long, env = "SSHLLM_BANNER")]
+    banner: Option<PathBuf>,
+
+    /// Send plain text without colors or styling (also set by a non-empty NO_COLOR)
+    #[arg(long)]
+    no_color: bool,
+
+    /// Seconds of client silence before sending an SSH keepalive, so NATs keep the connection (0 disables)
+    #[arg(long = "keepalive", default_value = "30", env = "SSHLLM_KEEPALIVE")]
+    keepalive_secs: u64,
+
+    /// Milliseconds before a failed authentication is answered
+    #[arg(long, default_value = "1000", env = "SSHLLM_AUTH_REJECTION_MS")]
+    auth_rejection_ms: u64,
+
+    /// Failed authentications allowed per connection before it is dropped (0 disables)
+    #[arg(long, default_value = "6", env = "SSHLLM_MAX_AUTH_ATTEMPTS")]
+    max_auth_attempts: u32,
+}
+
+/// Parse `--bind`, also accepting IPv6 addresses in brackets (`[::1]`)
+fn parse_bind_address(value: &str) -> Result<IpAddr, String> {
+    let address = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')).unwrap_or(value);
+    address
+        .parse()
+        .map_err(|_| format!("'{}' is not an IP address (e.g. 0.0.0.0, 127.0.0.1, :: or ::1)", value))
+}
+
+/// Whether an argument was left at its default rather than given as a flag or env var
+fn is_default(matches: &ArgMatches, id: &str) -> bool {
+    matches!(matches.value_source(id), None | Some(ValueSource::DefaultValue))
+}
+
+impl Args {
+    /// Take settings from a config file wherever no flag or env var was given
+    fn merge_file(&mut self, file: FileConfig, matches: &ArgMatches) {
+        if let Some(bind) = file.bind
+            && is_default(matches, "bind")
+        {
+            self.bind = bind;
+        }
+        if let Some(port) = file.port
+            && is_default(matches, "port")
+        {
+            self.port = port;
+        }
+        if let Some(url) = file.api_base_url
+            && is_default(matches, "api_url")
+        {
+            self.api_url = Some(url);
+        }
+        if let Some(endpoints) = file.fallback_endpoints
+            && is_default(matches, "fallback_endpoints")
+        {
+            self.fallback_endpoints = endpoints;
+        }
+        if let Some(model) = file.model
+            && is_default(matches, "model")
+        {
+            self.model = model;
+        }
+        if let Some(key) = file.api_key
+            && is_default(matches, "api_key")
+        {
+            self.api_key = Some(key);
+        }
+        if let Some(prompt) = file.system_prompt
+            && is_default(matches, "system_prompt")
+        {
+            self.system_prompt = Some(prompt);
+        }
+        if let Some(dir) = file.logs_dir
+            && is_default(matches, "logs")
+        {
+            self.logs = dir;
+        }
+        if let Some(path) = file.host_key_path
+            && is_default(matches, "host_keys")
+        {
+            self.host_keys = vec![path];
+        }
+    }
+}
+
+/// Check an endpoint URL and drop what requests append to it themselves: a trailing
+/// slash, or the backend's chat path copied along from API docs
+fn normalize_endpoint(url: &str, backend: Backend) -> Result<String> {
+    let parsed = reqwest::Url::parse(url).with_context(|| format!("invalid endpoint URL {:?}", url))?;
+    // `localhost:8080` parses with `localhost` as the scheme
+    if !matches!(parsed.scheme(), "http" | "https") {
+        anyhow::bail!("endpoint URL {:?} must start with http:// or https://", url);
+    }
+    
+    let trimmed = url.trim_end_matches('/');
+    let chat_path = backend.chat_path();
+    if backend != Backend::Azure
+        && let Some(base) = trimmed.strip_suffix(chat_path)
+    {
+        warn!("Endpoint {} already ends in {}, which is added to every request; using {}", url, chat_path, base);
+        return Ok(base.to_string());
+    }
+    if trimmed.contains(chat_path) || (backend == Backend::Azure && trimmed.contains("/openai/")) {
+        warn!("Endpoint {} looks like a full request URL; it should be the API base the chat path is added to", url);
+    }
+    Ok(trimmed.to_string())
+}
+
+/// Parse `--header KEY=VALUE` flags, rejecting names and values HTTP doesn't allow
+fn parse_headers(headers: &[String]) -> Result<HashMap<String, String>> {
+    headers
+        .iter()
+        .map(|header| {
+            let Some((name, value)) = header.split_once('=') else {
+                anyhow::bail!("--header must look like KEY=VALUE, got {:?}", header);
+            };
+            let (name, value) = (name.trim(), value.trim());
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {:?}", name))?;
+            reqwest::header::HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header {}", name))?;
+            Ok((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Proxy variables the HTTP client honours when `--proxy` isn't given
+const PROXY_ENV_VARS: &[&str] = &["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"];
+
+/// `url` with any password masked, for logging
+fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Certificates from a PEM bundle, or a single DER certificate
+fn load_ca_certs(path: &Path) -> Result<Vec<reqwest::Certificate>> {
+    let bytes = std::fs::read(path)?;
+    if let Ok(certs) = reqwest::Certificate::from_pem_bundle(&bytes)
+        && !certs.is_empty()
+    {
+        return Ok(certs);
+    }
+    Ok(vec![reqwest::Certificate::from_der(&bytes).context("not a PEM or DER certificate")?])
+}
+
+/// Bits of generated RSA host keys
+const RSA_HOST_KEY_BITS: usize = 3072;
+
+/// Load every configured host key, generating missing ones on first start
+fn load_host_keys(config: &Config) -> Result<Vec<PrivateKey>> {
+    config.host_key_paths.iter().map(|path| load_host_key(path)).collect()
+}
+
+/// Load a host key, or generate one: RSA if the file name mentions `rsa`, Ed25519 otherwise
+fn load_host_key(host_key_path: &Path) -> Result<PrivateKey> {
+    if let Some(parent) = host_key_path.parent()
+        && let Err(e) = std::fs::create_dir_all(parent)
+    {
+        anyhow::bail!(
+            "cannot create key directory {}: {}; check permissions or set --host-key to a writable location",
+            parent.display(),
+            e
+        );
+    }
+
+    if host_key_path.exists() {
+        info!("Loading host key from {}", host_key_path.display());
+        let key_data = std::fs::read_to_string(host_key_path)?;
+        Ok(PrivateKey::from_openssh(key_data.as_bytes())?)
+    } else {
+        info!("Generating new host key at {}", host_key_path.display());
+        let is_rsa = host_key_path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().to_lowercase().contains("rsa"));
+        let key = if is_rsa {
+            PrivateKey::from(RsaKeypair::random(&mut OsRng, RSA_HOST_KEY_BITS)?)
+        } else {
+            PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?
+        };
+        let key_data = key.to_openssh(LineEnding::LF)?;
+        std::fs::write(host_key_path, key_data.as_bytes())?;
+        Ok(key)
+    }
+}
+
+/// Storage for chat logs and summaries, opening the SQLite database when that backend is chosen
+fn open_log_store(config: &Config) -> Result<LogStore> {
+    match config.log_backend {
+        LogBackend::Files => Ok(LogStore::default()),
+        #[cfg(feature = "sqlite")]
+        LogBackend::Sqlite => {
+            std::fs::create_dir_all(&config.logs_dir)?;
+            let path = config.logs_dir.join("sshllm.db");
+            LogStore::sqlite(&path).with_context(|| format!("cannot open log database {}", path.display()))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        LogBackend::Sqlite => anyhow::bail!("--log-backend sqlite needs a build with `--features sqlite`"),
+    }
+}
+
+/// Wait for SIGINT (Ctrl+C) or, on Unix, SIGTERM
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    if let Ok(mut term) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        return tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = term.recv() => "SIGTERM",
+        };
+    }
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
     // Initialize logging
+    let timer = tracing_subscriber::fmt::time::ChronoUtc::new("%Y-%m-%dT%H:%M:%SZ".to_string());
+    let (text, json) = match args.log_format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer().with_target(false).compact().with_timer(timer)), None),
+        LogFormat::Json => (None, Some(tracing_subscriber::fmt::layer().with_target(false).json().flatten_event(true).with_timer(timer))),
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer()
-            .with_target(false)
-            .compact()
-            .with_timer(tracing_subscriber::fmt::time::ChronoUtc::new("%Y-%m-%dT%H:%M:%SZ".to_string())))
+        .with(text)
+        .with(json)
         .with(tracing_subscriber::EnvFilter::from_default_env().add_directive("sshllm=info".parse()?))
         .init();
 
-    let args = Args::parse();
+    if let Some(path) = args.config.clone() {
+        let file = FileConfig::from_file(&path)
+            .with_context(|| format!("cannot load config file {}", path.display()))?;
+        args.merge_file(file, &matches);
+    }
+    let Some(ref api_url) = args.api_url else {
+        anyhow::bail!("no LLM endpoint given: pass --endpoint, set SSHLLM_API_URL or add api_base_url to the config file");
+    };
+    let api_url = normalize_endpoint(api_url, args.backend)?;
+    let fallback_endpoints = args
+        .fallback_endpoints
+        .iter()
+        .map(|url| normalize_endpoint(url, args.backend))
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(t) = args.temperature
+        && !(0.0..=2.0).contains(&t)
+    {
+        anyhow::bail!("--temperature must be between 0.0 and 2.0, got {}", t);
+    }
+    if let Some(p) = args.top_p
+        && !(0.0..=1.0).contains(&p)
+    {
+        anyhow::bail!("--top-p must be between 0.0 and 1.0, got {}", p);
+    }
+
+    let system_prompt = match args.system_prompt_file {
+        Some(ref path) => {
+            let prompt = std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read system prompt file {}", path.display()))?;
+            let prompt = prompt.strip_suffix('\n').unwrap_or(&prompt);
+            prompt.strip_suffix('\r').unwrap_or(prompt).to_string()
+        }
+        None => args.system_prompt.unwrap_or_else(|| "You are a helpful AI assistant. Be concise and friendly.".to_string()),
+    };
+
+    let translations = match args.translations {
+        Some(ref path) => i18n::load_translations(path)
+            .with_context(|| format!("cannot load translations from {}", path.display()))?,
+        None => Default::default(),
+    };
+
+    let rewrite_rules = match args.rewrite_rules {
+        Some(ref path) => rewrite::load_rules(path)
+            .with_context(|| format!("cannot load rewrite rules from {}", path.display()))?,
+        None => Vec::new(),
+    };
+
+    let extra_headers = parse_headers(&args.headers)?;
+    if let Some(ref proxy) = args.proxy {
+        reqwest::Proxy::all(proxy).with_context(|| format!("invalid --proxy {:?}", redact_url(proxy)))?;
+    }
+
+    let ca_certs = match args.ca_cert {
+        Some(ref path) => {
+            load_ca_certs(path).with_context(|| format!("cannot load CA certificate from {}", path.display()))?
+        }
+        None => Vec::new(),
+    };
+
+    let personas = match args.personas_dir {
+        Some(ref dir) => {
+            let personas = personas::load_personas(dir)
+                .with_context(|| format!("cannot load personas from {}", dir.display()))?;
+            info!("Loaded {} personas from {}", personas.len(), dir.display());
+            personas
+        }
+        None => Default::default(),
+    };
+
+    if let Some(ref path) = args.authorized_keys {
+        let keys = auth::load_authorized_keys(path)
+            .with_context(|| format!("cannot load authorized keys from {}", path.display()))?;
+        info!("Allowlist mode: {} authorized keys in {}", keys.len(), path.display());
+    }
 
     // Build config
     let config = Arc::new(Config {
+        bind: args.bind,
         port: args.port,
-        api_base_url: args.api_url.clone(),
+        api_base_url: api_url,
+        fallback_endpoints,
+        backend: args.backend,
+        azure_deployment: args.azure_deployment.clone(),
+        azure_api_version: args.azure_api_version.clone(),
         model: args.model.clone(),
+        temperature: args.temperature,
+        top_p: args.top_p,
+        max_tokens: args.max_tokens,
+        seed: args.seed,
         api_key: args.api_key.clone(),
-        system_prompt: args.system_prompt.unwrap_or_else(|| "You are a helpful AI assistant. Be concise and friendly.".to_string()),
+        extra_headers,
+        proxy: args.proxy.clone(),
+        ca_certs,
+        insecure_skip_verify: args.insecure_skip_verify,
+        system_prompt,
+        input_template: args.input_template.clone(),
+        prompt_string: args.prompt_string.clone(),
+        translations,
+        max_request_bytes: args.max_request_bytes,
+        request_timeout_secs: args.request_timeout_secs,
+        max_retries: args.max_retries,
+        retry_on_context_error: !args.no_context_retry,
+        allow_model_ansi: args.allow_model_ansi,
+        rewrite_rules,
+        personas,
+        enable_tools: args.tools,
+        stream: !args.no_stream,
         logs_dir: args.logs.clone(),
+        log_backend: args.log_backend,
+        host_key_paths: args.host_keys.clone(),
+        on_connect_cmd: args.on_connect_cmd.clone(),
+        on_disconnect_cmd: args.on_disconnect_cmd.clone(),
+        admin_keys: args.admin_keys.clone(),
+        kiosk: args.kiosk,
+        max_conns_per_ip: args.max_conns_per_ip,
+        max_concurrent_llm: args.max_concurrent_llm,
+        cache_size: if args.cache { args.cache_size } else { 0 },
+        max_sessions: args.max_sessions,
+        idle_timeout_secs: args.idle_timeout_secs,
+        reattach_grace_secs: args.reattach_grace_secs,
+        daily_message_limit: args.daily_message_limit,
+        authorized_keys: args.authorized_keys.clone(),
+        require_key: args.require_key,
+        trust_proxy: args.trust_proxy,
+        summarize_after: args.summarize_after,
+        max_history_messages: args.max_history_messages,
+        max_loaded_history: args.max_loaded_history,
+        max_log_bytes: args.max_log_bytes,
+        log_retention_days: args.log_retention_days,
+        context_size: args.context_size,
+        banner: args.banner.clone(),
+        keepalive_secs: args.keepalive_secs,
+        auth_rejection_ms: args.auth_rejection_ms,
+        max_auth_attempts: args.max_auth_attempts,
+        // https://no-color.org: any non-empty value turns colors off
+        color: !args.no_color && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
     });
 
-    // Generate or load host key
-    let host_key_path = &args.host_key;
-    if let Some(parent) = host_key_path.parent() {
-        std::fs::create_dir_all(parent)?;
-    }
+    let log_store = open_log_store(&config)?;
+    let host_keys = load_host_keys(&config)?;
+    let response_cache = (config.cache_size > 0).then(|| Arc::new(ResponseCache::new(config.cache_size)));
+    let llm = Arc::new(LlmClient::new(config.clone(), response_cache).context("cannot set up the HTTP client for LLM requests")?);
 
-    let host_key = if host_key_path.exists() {
-        info!("Loading host key from {}", host_key_path.display());
-        let key_data = std::fs::read_to_string(host_key_path)?;
-        PrivateKey::from_openssh(key_data.as_bytes())?
-    } else {
-        info!("Generating new host key at {}", host_key_path.display());
-        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?;
-        let key_data = key.to_openssh(LineEnding::LF)?;
-        std::fs::write(host_key_path, key_data.as_bytes())?;
-        key
-    };
-
-    info!("Starting sshllm server on port {}", config.port);
+    let addr = SocketAddr::new(config.bind, config.port);
+    info!("Starting sshllm server on {}", addr);
     info!("LLM endpoint: {}", config.api_base_url);
+    if !config.fallback_endpoints.is_empty() {
+        info!("Fallback endpoints: {}", config.fallback_endpoints.join(", "));
+    }
+    match config.proxy {
+        Some(ref proxy) => info!("LLM proxy: {}", redact_url(proxy)),
+        None => {
+            for var in PROXY_ENV_VARS {
+                if let Ok(proxy) = std::env::var(var)
+                    && !proxy.is_empty()
+                {
+                    info!("LLM proxy from {}: {}", var, redact_url(&proxy));
+                }
+            }
+        }
+    }
     info!("Model: {}", config.model);
+    if config.insecure_skip_verify {
+        warn!("TLS certificate verification is DISABLED for LLM requests (--insecure-skip-verify); anyone on the path can read and alter them");
+    }
     info!("Logs directory: {}", config.logs_dir.display());
+    if config.kiosk {
+        info!("Kiosk mode enabled: no chat content or user data will be stored");
+    }
+    if config.enable_tools && !config.backend.openai_schema() {
+        warn!("--tools is only supported with the openai and azure backends; tool calling is disabled");
+    }
 
     // Configure SSH server
     let ssh_config = russh::server::Config {
-        auth_rejection_time: std::time::Duration::from_secs(1),
-        keys: vec![host_key],
+        auth_rejection_time: std::time::Duration::from_millis(config.auth_rejection_ms),
+        keys: host_keys,
+        methods: config.auth_methods(),
+        // Connections whose keepalives go unanswered (keepalive_max times) are dropped by russh
+        keepalive_interval: (config.keepalive_secs > 0).then(|| std::time::Duration::from_secs(config.keepalive_secs)),
         ..Default::default()
     };
 
+    let stats = Arc::new(ServerStats::new());
+
+    if args.stats_interval > 0 {
+        let stats = stats.clone();
+        let period = std::time::Duration::from_secs(args.stats_interval);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                info!(
+                    "Stats: {} active sessions, {} total sessions, {} messages, {} backend errors",
+                    stats.active_sessions(),
+                    stats.total_sessions(),
+                    stats.messages(),
+                    stats.backend_errors()
+                );
+            }
+        });
+    }
+
+    if let Some(addr) = args.metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("cannot bind metrics address {}", addr))?;
+        info!("Serving Prometheus metrics on http://{}/metrics", addr);
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, stats).await {
+                warn!("Metrics server stopped: {}", e);
+            }
+        });
+    }
+
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
     let mut server = SshServer {
         config: config.clone(),
+        stats: stats.clone(),
         id: 0,
-        clients: Arc::new(Mutex::new(HashMap::new())),
+        clients: clients.clone(),
+        limiter: Arc::new(ConnectionLimiter::new(config.max_conns_per_ip)),
+        llm_queue: Arc::new(RequestQueue::new(config.max_concurrent_llm)),
+        llm,
+        log_store: Arc::new(log_store),
+        detached: Default::default(),
     };
 
-    let addr: std::net::SocketAddr = format!("0.0.0.0:{}", config.port).parse()?;
-    server.run_on_address(Arc::new(ssh_config), addr).await?;
+    server.spawn_reaper();
+    server.spawn_idle_sweeper();
+    let detached = server.detached.clone();
+
+    let socket = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("cannot bind SSH address {}", addr))?;
+    let ssh_config = Arc::new(ssh_config);
+
+    if config.trust_proxy {
+        info!("Reading client addresses from PROXY protocol headers");
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
+        tokio::select! {
+            result = proxy::run_on_socket(&mut server, ssh_config, &socket, shutdown_tx.clone()) => result?,
+            signal = shutdown_signal() => {
+                info!("Received {}, shutting down", signal);
+                server::shutdown_clients(&config, &stats, &clients, &detached).await;
+                let _ = shutdown_tx.send("Server shutting down".to_string());
+            }
+        }
+        return Ok(());
+    }
+
+    let running = server.run_on_socket(ssh_config, &socket);
+    let handle = running.handle();
+
+    tokio::select! {
+        result = running => result?,
+        signal = shutdown_signal() => {
+            info!("Received {}, shutting down", signal);
+            server::shutdown_clients(&config, &stats, &clients, &detached).await;
+            handle.shutdown("Server shutting down".to_string());
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    
+    /// Arguments parsed from `argv` with a config file's settings merged in
+    fn args_with_file(argv: &[&str], file: &str) -> Args {
+        let matches = Args::command().get_matches_from(std::iter::once("sshllm").chain(argv.iter().copied()));
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        args.merge_file(toml::from_str(file).unwrap(), &matches);
+        args
+    }
+    
+    #[test]
+    fn config_file_host_key_path_replaces_the_defaults() {
+        let args = args_with_file(&[], "host_key_path = \"/etc/sshllm/host_ed25519\"");
+        assert_eq!(args.host_keys, [PathBuf::from("/etc/sshllm/host_ed25519")]);
+        
+        // A flag still wins over the file
+        let args = args_with_file(&["--host-key", "flag_key"], "host_key_path = \"/etc/sshllm/host_ed25519\"");
+        assert_eq!(args.host_keys, [PathBuf::from("flag_key")]);
+    }
+    
+    #[test]
+    fn host_keys_are_loaded_from_the_configured_paths() {
+        let dir = TempDir::new();
+        let path = dir.path().join("keys").join("custom_ed25519");
+        let config = Config { host_key_paths: vec![path.clone()], ..crate::test_support::config("http://localhost") };
+        
+        let generated = load_host_keys(&config).unwrap();
+        assert_eq!(generated.len(), 1);
+        assert!(path.exists());
+        
+        let loaded = load_host_keys(&config).unwrap();
+        assert_eq!(loaded[0].public_key(), generated[0].public_key());
+    }
+    
+    #[test]
+    fn normalize_endpoint_drops_trailing_slashes() {
+        assert_eq!(normalize_endpoint("http://localhost:8080/v1/", Backend::OpenAiCompatible).unwrap(), "http://localhost:8080/v1");
+        assert_eq!(normalize_endpoint("https://api.example.com//", Backend::Anthropic).unwrap(), "https://api.example.com");
+    }
+    
+    #[test]
+    fn normalize_endpoint_requires_a_scheme() {
+        assert!(normalize_endpoint("localhost:8080/v1", Backend::OpenAiCompatible).is_err());
+        assert!(normalize_endpoint("api.example.com/v1", Backend::OpenAiCompatible).is_err());
+        assert!(normalize_endpoint("ftp://example.com/v1", Backend::OpenAiCompatible).is_err());
+    }
+    
+    #[test]
+    fn normalize_endpoint_strips_the_chat_path_but_keeps_the_base_path() {
+        assert_eq!(
+            normalize_endpoint("http://localhost:8080/v1/chat/completions", Backend::OpenAiCompatible).unwrap(),
+            "http://localhost:8080/v1"
+        );
+        assert_eq!(normalize_endpoint("http://localhost:11434/api/chat/", Backend::Ollama).unwrap(), "http://localhost:11434");
+        assert_eq!(normalize_endpoint("http://gateway/team/v1", Backend::OpenAiCompatible).unwrap(), "http://gateway/team/v1");
+        // Azure's chat path follows the deployment, so nothing is stripped
+        assert_eq!(
+            normalize_endpoint("https://res.openai.azure.com/chat/completions", Backend::Azure).unwrap(),
+            "https://res.openai.azure.com/chat/completions"
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use crate::stats::{LLM_DURATION_BUCKETS, ServerStats};
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+/// Largest request head read before answering
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// How long a scraper may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Render the counters in the Prometheus text exposition format
+fn render(stats: &ServerStats) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+    };
+    metric("sshllm_active_sessions", "gauge", "Chat sessions currently open.", stats.active_sessions());
+    metric("sshllm_sessions_total", "counter", "Chat sessions opened since start.", stats.total_sessions());
+    metric("sshllm_messages_total", "counter", "User messages sent to the LLM.", stats.messages());
+    metric("sshllm_llm_errors_total", "counter", "Failed LLM requests.", stats.backend_errors());
+    metric("sshllm_uptime_seconds", "gauge", "Seconds since the server started.", stats.uptime_secs());
+
+    let (counts, sum) = stats.llm_durations();
+    let name = "sshllm_llm_request_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Time taken by LLM requests.\n# TYPE {} histogram", name, name);
+    for (bound, count) in LLM_DURATION_BUCKETS.iter().zip(&counts) {
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+    }
+    let total = counts.last().copied().unwrap_or(0);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}\n{}_sum {}\n{}_count {}", name, total, name, sum, name, total);
+    out
+}
+
+/// Answer one HTTP request: the metrics for `GET /metrics`, 404 for anything else
+async fn respond(mut stream: TcpStream, stats: &ServerStats) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    let (status, content_type, body) = if method == Some(b"GET") && path.is_some_and(|p| p == b"/metrics" || p.starts_with(b"/metrics?")) {
+        ("200 OK", "text/plain; version=0.0.4", render(stats))
+    } else {
+        ("404 Not Found", "text/plain", "Not found\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serve `/metrics` on `listener` until the process exits
+pub async fn serve(listener: TcpListener, stats: Arc<ServerStats>) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &stats)).await {
+                Ok(Err(e)) => warn!(peer = %peer, error = %e, "Metrics request failed"),
+                Err(_) => warn!(peer = %peer, "Metrics request timed out"),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+}
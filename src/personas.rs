@@ -0,0 +1,27 @@
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Persona system prompts keyed by lowercase name, sorted for listing
+pub type Personas = BTreeMap<String, String>;
+
+/// Load every `.txt` and `.md` file in `dir` as a persona named after the file
+pub fn load_personas(dir: &Path) -> anyhow::Result<Personas> {
+    let mut personas = Personas::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.extension().is_some_and(|ext| ext == "txt" || ext == "md") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let prompt = std::fs::read_to_string(&path)
+            .with_context(|| format!("cannot read persona {}", path.display()))?;
+        let prompt = prompt.trim();
+        if !prompt.is_empty() {
+            personas.insert(name.to_lowercase(), prompt.to_string());
+        }
+    }
+    Ok(personas)
+}
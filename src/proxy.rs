@@ -0,0 +1,255 @@
+use crate::server::SshServer;
+use russh::server::{run_stream, Config, Server as _};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tracing::warn;
+
+/// Fixed start of a PROXY protocol v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header line, `\r\n` included
+const V1_MAX_LEN: usize = 107;
+
+/// How long a connection may take to send its header before it is dropped
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept (e.g. out of file descriptors) before trying again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Source address from a v1 line such as `PROXY TCP4 203.0.113.7 10.0.0.1 51234 2222`;
+/// `None` for `PROXY UNKNOWN`
+fn parse_v1(line: &str) -> std::io::Result<Option<SocketAddr>> {
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(invalid("bad PROXY v1 header"));
+    }
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unsupported PROXY v1 protocol")),
+    }
+    let ip: IpAddr = fields.next().and_then(|f| f.parse().ok()).ok_or_else(|| invalid("bad PROXY v1 source address"))?;
+    let _destination = fields.next();
+    let port: u16 = fields.next().and_then(|f| f.parse().ok()).ok_or_else(|| invalid("bad PROXY v1 source port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Source address from the address block of a v2 header; `None` for LOCAL
+/// connections (e.g. balancer health checks) and non-IP families
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    match command {
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    let port = |at: usize| addresses.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    match family >> 4 {
+        0x1 => {
+            let ip: [u8; 4] = addresses.get(..4).and_then(|b| b.try_into().ok()).ok_or_else(|| invalid("short PROXY v2 header"))?;
+            let port = port(8).ok_or_else(|| invalid("short PROXY v2 header"))?;
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        0x2 => {
+            let ip: [u8; 16] = addresses.get(..16).and_then(|b| b.try_into().ok()).ok_or_else(|| invalid("short PROXY v2 header"))?;
+            let port = port(32).ok_or_else(|| invalid("short PROXY v2 header"))?;
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Read a PROXY protocol v1 or v2 header, leaving the stream at the start of the SSH traffic
+async fn read_header(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<SocketAddr>> {
+    let first = stream.read_u8().await?;
+    if first == b'P' {
+        let mut line = vec![first];
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("bad PROXY v1 header"))?;
+        return parse_v1(line);
+    }
+
+    let mut header = [0u8; 16];
+    header[0] = first;
+    stream.read_exact(&mut header[1..]).await?;
+    if &header[..12] != V2_SIGNATURE || header[12] >> 4 != 2 {
+        return Err(invalid("connection didn't start with a PROXY protocol header"));
+    }
+    let mut addresses = vec![0u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream.read_exact(&mut addresses).await?;
+    parse_v2(header[12] & 0x0f, header[13], &addresses)
+}
+
+/// Accept connections that start with a PROXY protocol header (as sent by a load
+/// balancer) and run SSH sessions on them with the client address it carries.
+///
+/// Headers are read in separate tasks so a slow connection can't hold up the others;
+/// sending on `shutdown` disconnects every session. Failed accepts are logged and
+/// retried, and session errors go to `handle_session_error` as with russh's own loop.
+pub async fn run_on_socket(
+    server: &mut SshServer,
+    config: Arc<Config>,
+    listener: &TcpListener,
+    shutdown: broadcast::Sender<String>,
+) -> std::io::Result<()> {
+    let (ready_tx, mut ready_rx) = mpsc::unbounded_channel::<(TcpStream, SocketAddr)>();
+    let (error_tx, mut error_rx) = mpsc::unbounded_channel::<russh::Error>();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (mut stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!(error = %e, "Could not accept a connection");
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                let ready_tx = ready_tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                        Ok(Ok(source)) => {
+                            let _ = ready_tx.send((stream, source.unwrap_or(peer)));
+                        }
+                        Ok(Err(e)) => warn!(peer = %peer, error = %e, "Dropping connection without a valid PROXY header"),
+                        Err(_) => warn!(peer = %peer, "Dropping connection: no PROXY header within {}s", HEADER_TIMEOUT.as_secs()),
+                    }
+                });
+            }
+            Some((stream, source)) = ready_rx.recv() => {
+                let handler = server.new_client(Some(source));
+                let config = config.clone();
+                let mut shutdown_rx = shutdown.subscribe();
+                let error_tx = error_tx.clone();
+                tokio::spawn(async move {
+                    if config.nodelay
+                        && let Err(e) = stream.set_nodelay(true)
+                    {
+                        warn!("set_nodelay() failed: {:?}", e);
+                    }
+                    let session = match run_stream(config, stream, handler).await {
+                        Ok(session) => session,
+                        Err(e) => {
+                            let _ = error_tx.send(e);
+                            return;
+                        }
+                    };
+                    let handle = session.handle();
+                    tokio::select! {
+                        reason = shutdown_rx.recv() => {
+                            let reason = reason.unwrap_or_default();
+                            let _ = handle.disconnect(russh::Disconnect::ByApplication, reason, String::new()).await;
+                        }
+                        result = session => {
+                            if let Err(e) = result {
+                                let _ = error_tx.send(e);
+                            }
+                        }
+                    }
+                });
+            }
+            Some(error) = error_rx.recv() => server.handle_session_error(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// v2 header for a PROXY command over TCP with the given family and address block
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    async fn read(bytes: &[u8]) -> (std::io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = bytes;
+        let source = read_header(&mut stream).await;
+        (source, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1_headers_carry_the_source_address() {
+        let (source, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 2222\r\nSSH-2.0-client\r\n").await;
+        assert_eq!(source.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"SSH-2.0-client\r\n");
+
+        let (source, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 2222\r\n").await;
+        assert_eq!(source.unwrap(), Some("[2001:db8::7]:51234".parse().unwrap()));
+
+        let (source, rest) = read(b"PROXY UNKNOWN\r\nSSH-2.0-client\r\n").await;
+        assert_eq!(source.unwrap(), None);
+        assert_eq!(rest, b"SSH-2.0-client\r\n");
+    }
+
+    #[tokio::test]
+    async fn bad_v1_headers_are_errors() {
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.extend([b'1'; V1_MAX_LEN]);
+        long.extend(b"\r\n");
+        assert!(read(&long).await.0.is_err());
+
+        assert!(parse_v1("PROXY UDP4 203.0.113.7 10.0.0.1 51234 2222").is_err());
+        assert!(parse_v1("PROXY TCP4 not-an-ip 10.0.0.1 51234 2222").is_err());
+        assert!(parse_v1("PROXY TCP4 203.0.113.7 10.0.0.1 99999 2222").is_err());
+        assert!(parse_v1("HELLO TCP4 203.0.113.7 10.0.0.1 51234 2222").is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_headers_carry_the_source_address() {
+        let ipv4 = [[203, 0, 113, 7], [10, 0, 0, 1]].concat();
+        let ports = [51234u16.to_be_bytes(), 2222u16.to_be_bytes()].concat();
+        let mut bytes = v2_header(0x1, 0x11, &[ipv4, ports.clone()].concat());
+        bytes.extend(b"SSH-2.0-client\r\n");
+        let (source, rest) = read(&bytes).await;
+        assert_eq!(source.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"SSH-2.0-client\r\n");
+
+        let source: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let ipv6 = [source.octets(), destination.octets()].concat();
+        let (source, _) = read(&v2_header(0x1, 0x21, &[ipv6, ports].concat())).await;
+        assert_eq!(source.unwrap(), Some("[2001:db8::7]:51234".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_local_connections_have_no_source_address() {
+        let mut bytes = v2_header(0x0, 0x00, &[]);
+        bytes.extend(b"SSH-2.0-client\r\n");
+        let (source, rest) = read(&bytes).await;
+        assert_eq!(source.unwrap(), None);
+        assert_eq!(rest, b"SSH-2.0-client\r\n");
+    }
+
+    #[tokio::test]
+    async fn bad_v2_headers_are_errors() {
+        // Address block too short for the IPv4 family it claims
+        assert!(read(&v2_header(0x1, 0x11, &[203, 0, 113, 7, 10, 0])).await.0.is_err());
+        // Header announces more address bytes than the stream holds
+        let mut truncated = v2_header(0x1, 0x11, &[0; 12]);
+        truncated.truncate(truncated.len() - 4);
+        assert!(read(&truncated).await.0.is_err());
+
+        let mut bad_signature = v2_header(0x1, 0x11, &[0; 12]);
+        bad_signature[4] = b'X';
+        assert!(read(&bad_signature).await.0.is_err());
+        assert!(read(b"SSH-2.0-client without a PROXY header\r\n").await.0.is_err());
+    }
+}
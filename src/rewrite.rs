@@ -0,0 +1,50 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::path::Path;
+
+/// A find/replace rule applied to assistant responses
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+struct RawRule {
+    pattern: String,
+    #[serde(default)]
+    replacement: String,
+}
+
+/// Load `[[rule]]` entries (`pattern`, `replacement`) from a TOML file
+pub fn load_rules(path: &Path) -> anyhow::Result<Vec<RewriteRule>> {
+    let contents = std::fs::read_to_string(path)?;
+    let file: RulesFile = toml::from_str(&contents)?;
+
+    file.rule
+        .into_iter()
+        .map(|raw| {
+            let pattern = Regex::new(&raw.pattern)
+                .map_err(|e| anyhow::anyhow!("invalid pattern {:?}: {}", raw.pattern, e))?;
+            Ok(RewriteRule {
+                pattern,
+                replacement: raw.replacement,
+            })
+        })
+        .collect()
+}
+
+/// Apply every rule in order
+pub fn apply_rules(rules: &[RewriteRule], text: &str) -> String {
+    let mut text = text.to_string();
+    for rule in rules {
+        text = rule.pattern.replace_all(&text, rule.replacement.as_str()).into_owned();
+    }
+    text
+}
@@ -1,27 +1,689 @@
-use crate::chat::ChatSession;
+use crate::auth::is_authorized;
+use crate::chat::{available_commands, ChatSession};
 use crate::config::Config;
-use crate::logger::ClientLogger;
+use crate::editor::LineEditor;
+use crate::hooks::spawn_hook;
+use crate::i18n::{detect_language, language_from_env, LOCALE_VARIABLES};
+use crate::limiter::{ConnectionLimiter, RequestQueue};
+use crate::llm::LlmClient;
+use crate::logger::{ClientLogger, LogStore};
+use crate::stats::ServerStats;
+use crate::terminal::{strip_control_sequences, wrap, WordWrapper};
+use crate::writer::ChannelWriter;
 use russh::keys::{PublicKey, PublicKeyBase64};
 use russh::server::{Auth, Handler, Msg, Session};
-use russh::{Channel, ChannelId, CryptoVec, MethodSet};
+use russh::{Channel, ChannelId};
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{error, info};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::{debug, error, info, warn};
+
+/// How often the reaper looks for state left behind by dead connections
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often idle sessions are checked for
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long before an idle disconnect the user is warned
+const IDLE_WARNING: Duration = Duration::from_secs(60);
+
+/// How long shutdown waits for goodbye messages to reach clients
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// Programs that file transfer tools run over exec (`scp -O`, rsync, git), refused instead of sent to the model
+const TRANSFER_COMMANDS: &[&str] = &["scp", "sftp-server", "rsync", "git-upload-pack", "git-receive-pack", "git-upload-archive"];
+
+/// Reply to subsystem and file transfer requests
+const NOT_SUPPORTED: &str = "This is a chat server; subsystems are not supported.";
+
+/// Whether an exec command starts a file transfer program rather than asking a question
+fn is_transfer_command(command: &str) -> bool {
+    let program = command.split_whitespace().next().unwrap_or_default();
+    let program = program.rsplit('/').next().unwrap_or(program).trim_matches(|c| c == '\'' || c == '"');
+    TRANSFER_COMMANDS.contains(&program)
+}
+
+/// Ask the terminal to wrap pastes in `ESC[200~` ... `ESC[201~`, and to stop again
+const BRACKETED_PASTE_ON: &str = "\x1b[?2004h";
+const BRACKETED_PASTE_OFF: &str = "\x1b[?2004l";
+
+/// Styled input prompt, e.g. `You: `, localized when a translation exists; `You (multiline): ` in multi-line mode
+fn prompt(config: &Config, language: Option<&str>, multiline: bool) -> String {
+    let text = config
+        .translation(language)
+        .and_then(|t| t.prompt.as_deref())
+        .unwrap_or(&config.prompt_string);
+    if !multiline {
+        return format!("\x1b[1;32m{}\x1b[0m", text);
+    }
+    match text.strip_suffix(": ") {
+        Some(label) => format!("\x1b[1;32m{} (multiline): \x1b[0m", label),
+        None => format!("\x1b[1;32m{}(multiline) \x1b[0m", text),
+    }
+}
+
+/// Built-in greeting art, used when no `--banner` file is set
+const DEFAULT_BANNER: &str = "\x1b[1;36m\
+    ╔═══════════════════════════════════════════════════════════════════╗\r\n\
+    ║                                                                   ║\r\n\
+    ║   ███████╗███████╗██╗  ██╗██╗     ██╗     ███╗   ███╗             ║\r\n\
+    ║   ██╔════╝██╔════╝██║  ██║██║     ██║     ████╗ ████║             ║\r\n\
+    ║   ███████╗███████╗███████║██║     ██║     ██╔████╔██║             ║\r\n\
+    ║   ╚════██║╚════██║██╔══██║██║     ██║     ██║╚██╔╝██║             ║\r\n\
+    ║   ███████║███████║██║  ██║███████╗███████╗██║ ╚═╝ ██║             ║\r\n\
+    ║   ╚══════╝╚══════╝╚═╝  ╚═╝╚══════╝╚══════╝╚═╝     ╚═╝             ║\r\n\
+    ║                                                                   ║\r\n\
+    ║                SSH LLM Chat Server                                ║\r\n\
+    ╚═══════════════════════════════════════════════════════════════════╝\x1b[0m";
+
+/// The operator's `--banner` file with `\r\n` line endings, or the built-in art
+fn banner(config: &Config) -> String {
+    let Some(ref path) = config.banner else {
+        return DEFAULT_BANNER.to_string();
+    };
+    match std::fs::read_to_string(path) {
+        Ok(text) => text.trim_end().replace("\r\n", "\n").replace('\n', "\r\n"),
+        Err(e) => {
+            warn!("Cannot read banner {}: {}; using the built-in one", path.display(), e);
+            DEFAULT_BANNER.to_string()
+        }
+    }
+}
+
+/// Submitted lines kept per client for Up/Down recall
+const INPUT_HISTORY_LIMIT: usize = 100;
+
+/// Label shown in front of assistant output
+const AI_LABEL: &str = "\x1b[1;36mAI:\x1b[0m ";
+
+/// Columns `AI_LABEL` takes up on screen
+const AI_LABEL_WIDTH: usize = 4;
+
+/// Frames of the thinking indicator, ASCII so any terminal can show them
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Time between spinner frames
+const SPINNER_INTERVAL: Duration = Duration::from_millis(120);
+
+/// Thinking indicator line showing spinner frame `frame`
+fn thinking_line(frame: usize) -> String {
+    format!("\r{}{} thinking...\r", AI_LABEL, SPINNER_FRAMES[frame % SPINNER_FRAMES.len()])
+}
+
+/// Animates the thinking indicator until stopped or dropped; no frame is drawn once `stop` returns
+struct Spinner {
+    stopped: Arc<std::sync::Mutex<bool>>,
+}
+
+impl Spinner {
+    fn start(writer: ChannelWriter) -> Self {
+        let stopped = Arc::new(std::sync::Mutex::new(false));
+        let flag = stopped.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SPINNER_INTERVAL);
+            interval.tick().await;
+            for frame in 1.. {
+                interval.tick().await;
+                // Frames are written under the lock, so none can land after `stop`
+                let stopped = flag.lock().unwrap_or_else(|e| e.into_inner());
+                if *stopped || writer.is_closed() {
+                    break;
+                }
+                writer.write(thinking_line(frame));
+            }
+        });
+        Self { stopped }
+    }
+    
+    fn stop(&self) {
+        *self.stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Run one turn through the chat session and write the reply, streaming it when enabled
+///
+/// Output is wrapped to `width` columns (0 leaves wrapping to the terminal). A message on
+/// `cancel` drops the turn, including the backend request, and shows a fresh prompt.
+async fn respond(
+    chat_session: Arc<Mutex<ChatSession>>,
+    writer: ChannelWriter,
+    busy: Arc<AtomicBool>,
+    cancel: oneshot::Receiver<()>,
+    input: String,
+    prompt: String,
+    width: usize,
+) {
+    // The client left before the request was made
+    if writer.is_closed() {
+        busy.store(false, Ordering::Release);
+        return;
+    }
+    let spinner = Spinner::start(writer.clone());
+    let streamed = AtomicBool::new(false);
+    
+    // Forward streamed text as it arrives, replacing the thinking indicator
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let process = async move {
+        let result = chat_session.lock().await.process_input(&input, &tx).await;
+        drop(tx);
+        result
+    };
+    let forward = async {
+        let mut wrapper = WordWrapper::new(width, AI_LABEL_WIDTH);
+        while let Some(text) = rx.recv().await {
+            if !streamed.swap(true, Ordering::Relaxed) {
+                spinner.stop();
+                writer.write(format!("\r\x1b[K{}", AI_LABEL));
+            }
+            writer.write(wrapper.push(&text).replace('\n', "\r\n"));
+        }
+        writer.write(wrapper.finish());
+    };
+    
+    let result = tokio::select! {
+        (result, ()) = async { tokio::join!(process, forward) } => Some(result),
+        Ok(()) = cancel => None,
+    };
+    spinner.stop();
+    let streamed = streamed.load(Ordering::Relaxed);
+
+    // Streamed output continues the AI line; otherwise overwrite the thinking indicator
+    let lead = if streamed { "\x1b[0m\r\n".to_string() } else { "\r\x1b[K".to_string() };
+
+    let Some(result) = result else {
+        let lead = if streamed { "\x1b[0m " } else { "\r\x1b[K" };
+        writer.write(format!("{}^C (cancelled)\r\n\r\n{}", lead, prompt));
+        busy.store(false, Ordering::Release);
+        return;
+    };
+
+    match result {
+        Ok(response) if streamed && response.is_empty() => {
+            writer.write(format!("{}\r\n{}", lead, prompt));
+        }
+        Ok(response) => {
+            let (label, column) = if streamed { ("", 0) } else { (AI_LABEL, AI_LABEL_WIDTH) };
+            let response = wrap(&response, width, column).replace('\n', "\r\n");
+            writer.write(format!("{}{}{}\r\n\r\n{}", lead, label, response, prompt));
+        }
+        Err(e) if e == "quit" => {
+            writer.write(format!("{}\r\nGoodbye!\r\n", lead));
+            writer.close();
+        }
+        Err(e) => {
+            writer.write(format!("{}\x1b[1;31mError: {}\x1b[0m\r\n\r\n{}", lead, e, prompt));
+        }
+    }
+    busy.store(false, Ordering::Release);
+}
+
+/// Answer a one-shot `ssh host "question"` with plain text, then exit
+async fn respond_exec(chat_session: Arc<Mutex<ChatSession>>, writer: ChannelWriter, input: String) {
+    if writer.is_closed() {
+        return;
+    }
+    // Streamed chunks are kept in the channel until the turn is over
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let result = chat_session.lock().await.process_input(&input, &tx).await;
+    drop(tx);
+    
+    let mut text = String::new();
+    while let Some(chunk) = rx.recv().await {
+        text.push_str(&chunk);
+    }
+    
+    let status = match result {
+        Ok(response) => {
+            text.push_str(&response);
+            0
+        }
+        Err(e) if e == "quit" => 0,
+        Err(e) => {
+            text.push_str(&format!("Error: {}", e));
+            1
+        }
+    };
+    
+    let mut text = strip_control_sequences(&text);
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    writer.write(text);
+    writer.exit(status);
+}
+
+/// Keys decoded from terminal escape sequences
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+    /// Bracketed paste markers around pasted text
+    PasteStart,
+    PasteEnd,
+}
+
+/// Result of reading a (possibly partial) escape sequence
+enum Escape {
+    Incomplete,
+    Key(Key),
+    /// Complete but unsupported sequence, dropped silently
+    Unknown,
+}
+
+/// Decode an escape sequence starting with ESC, e.g. `\x1b[A`
+fn parse_escape(seq: &[u8]) -> Escape {
+    match seq {
+        [0x1b] | [0x1b, b'[' | b'O'] => Escape::Incomplete,
+        [0x1b, b'[' | b'O', rest @ ..] => match rest.last() {
+            Some(0x40..=0x7e) => match rest {
+                b"A" => Escape::Key(Key::Up),
+                b"B" => Escape::Key(Key::Down),
+                b"C" => Escape::Key(Key::Right),
+                b"D" => Escape::Key(Key::Left),
+                b"H" | b"1~" | b"7~" => Escape::Key(Key::Home),
+                b"F" | b"4~" | b"8~" => Escape::Key(Key::End),
+                b"3~" => Escape::Key(Key::Delete),
+                b"200~" => Escape::Key(Key::PasteStart),
+                b"201~" => Escape::Key(Key::PasteEnd),
+                _ => Escape::Unknown,
+            },
+            // Parameter bytes; give up on runaway sequences
+            _ if seq.len() < 16 => Escape::Incomplete,
+            _ => Escape::Unknown,
+        },
+        // ESC followed by anything else (Alt+key)
+        _ => Escape::Unknown,
+    }
+}
 
 /// Per-client state
 pub struct ClientState {
-    pub handle: russh::server::Handle,
+    pub identity: String,
+    /// The session channel; closing any other channel of the connection leaves the state alone
+    pub channel: ChannelId,
+    pub writer: ChannelWriter,
     pub chat_session: Arc<Mutex<ChatSession>>,
-    pub input_buffer: String,
+    pub input: LineEditor,
+    /// Bytes of a multibyte UTF-8 character that hasn't fully arrived yet
+    pub utf8_pending: Vec<u8>,
+    /// Bytes of an escape sequence (arrow keys etc.) still being read
+    pub escape_pending: Vec<u8>,
+    /// Inside a bracketed paste, where newlines don't submit
+    pub pasting: bool,
+    /// Last pasted byte was `\r`, so a following `\n` belongs to the same line break
+    pub paste_cr: bool,
+    /// Finished lines of a multi-line paste, each ending in `\n`; sent with the line being edited
+    pub pasted: String,
+    /// `/multiline` mode: Enter adds a line, a lone `.` or Ctrl+D sends
+    pub multiline: bool,
+    /// Previously submitted lines, oldest first
+    pub input_history: Vec<String>,
+    /// Position in `input_history` while recalling; `input_history.len()` means a fresh line
+    pub history_index: usize,
+    /// Prompt as last shown to this client, for redrawing the input line
+    pub prompt: String,
+    /// Last keystroke, or the last moment a response was still being generated
+    pub last_activity: Instant,
+    /// Whether the idle warning was shown since the last keystroke
+    pub idle_warned: bool,
+    /// Set while a response is being generated; cleared by the response task
+    pub busy: Arc<AtomicBool>,
+    /// Stops the response being generated (Ctrl+C)
+    pub cancel: Option<oneshot::Sender<()>>,
+    /// Terminal size from the pty request, 0 until one arrives
+    pub columns: usize,
+    pub rows: usize,
+    /// Picked up a session left by a dropped connection, so the welcome says so
+    pub reattached: bool,
+}
+
+impl ClientState {
+    /// The whole message: pasted lines followed by the line being edited
+    fn take_input(&mut self) -> String {
+        let line = self.input.take();
+        std::mem::take(&mut self.pasted) + &line
+    }
+
+    /// Replace the input with `text`, which may span lines; returns what to echo after the prompt
+    fn set_input(&mut self, text: String) -> String {
+        let (pasted, line) = match text.rfind('\n') {
+            Some(i) => (text[..=i].to_string(), text[i + 1..].to_string()),
+            None => (String::new(), text),
+        };
+        let echo = format!("{}{}", pasted.replace('\n', "\r\n"), line);
+        self.pasted = pasted;
+        self.input.set(line);
+        echo
+    }
+
+    /// In multi-line mode, add the line to the message unless it ends it; returns whether Enter was used up
+    fn continue_multiline(&mut self) -> bool {
+        if !self.multiline {
+            return false;
+        }
+        match self.input.text().trim() {
+            "." => {
+                self.input.take();
+                false
+            }
+            "/multiline" if self.pasted.is_empty() => false,
+            _ => {
+                let line = self.input.take();
+                self.pasted.push_str(&line);
+                self.pasted.push('\n');
+                true
+            }
+        }
+    }
+
+    /// Handle a control byte inside a bracketed paste; returns the bytes to echo
+    fn paste_control(&mut self, byte: u8) -> String {
+        let after_cr = std::mem::replace(&mut self.paste_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => String::new(),
+            b'\r' | b'\n' => {
+                let line = self.input.take();
+                self.pasted.push_str(&line);
+                self.pasted.push('\n');
+                "\r\n".to_string()
+            }
+            b'\t' => self.input.insert("    "),
+            _ => String::new(),
+        }
+    }
+
+    /// Remember a submitted line and go back to a fresh line
+    fn push_history(&mut self, line: &str) {
+        if !line.is_empty() && self.input_history.last().map(String::as_str) != Some(line) {
+            self.input_history.push(line.to_string());
+            if self.input_history.len() > INPUT_HISTORY_LIMIT {
+                self.input_history.remove(0);
+            }
+        }
+        self.history_index = self.input_history.len();
+    }
+
+    /// Step through history (`back` = older) and return the recalled line, if it changed
+    fn recall_history(&mut self, back: bool) -> Option<String> {
+        let index = if back {
+            self.history_index.checked_sub(1)?
+        } else if self.history_index < self.input_history.len() {
+            self.history_index + 1
+        } else {
+            return None;
+        };
+        self.history_index = index;
+        Some(self.input_history.get(index).cloned().unwrap_or_default())
+    }
+
+    /// Complete a slash command at the end of the line; returns the bytes to echo
+    fn complete_command(&mut self, config: &Config) -> String {
+        let line = self.input.text();
+        if !line.starts_with('/') || line.contains(' ') || !self.input.at_end() {
+            return "\x07".to_string();
+        }
+        let candidates: Vec<&str> = available_commands(config, &self.identity)
+            .into_iter()
+            .filter(|c| c.starts_with(line))
+            .collect();
+        
+        match candidates.as_slice() {
+            [] => "\x07".to_string(),
+            [only] => {
+                let rest = format!("{} ", &only[line.len()..]);
+                self.input.insert(&rest)
+            }
+            [first, others @ ..] => {
+                // Extend to the longest shared prefix, or list the choices if there is none to add
+                let common = others.iter().fold(first.len(), |len, c| {
+                    first.bytes().zip(c.bytes()).take(len).take_while(|(a, b)| a == b).count()
+                });
+                if common > line.len() {
+                    let rest = first[line.len()..common].to_string();
+                    self.input.insert(&rest)
+                } else {
+                    format!("\r\n{}\r\n{}{}", candidates.join("  "), self.prompt, self.input.redraw())
+                }
+            }
+        }
+    }
+
+    /// Record a new terminal size; returns whether the width changed
+    fn resize(&mut self, columns: u32, rows: u32) -> bool {
+        let changed = self.columns != columns as usize;
+        self.columns = columns as usize;
+        self.rows = rows as usize;
+        changed
+    }
 }
 
 /// SSH Server
 pub struct SshServer {
     pub config: Arc<Config>,
+    pub stats: Arc<ServerStats>,
     pub id: usize,
-    pub clients: Arc<Mutex<HashMap<usize, ClientState>>>,
+    pub clients: Clients,
+    pub limiter: Arc<ConnectionLimiter>,
+    pub llm_queue: Arc<RequestQueue>,
+    /// Backend client shared by all sessions, with the response cache when `--cache` is set
+    pub llm: Arc<LlmClient>,
+    pub log_store: Arc<LogStore>,
+    pub detached: DetachedSessions,
+}
+
+pub type Clients = Arc<Mutex<HashMap<usize, ClientState>>>;
+
+/// A key user's session kept after their connection dropped, for them to reattach to
+pub struct DetachedSession {
+    chat_session: Arc<Mutex<ChatSession>>,
+    input_history: Vec<String>,
+    /// Still set if the connection dropped while a response was being generated
+    busy: Arc<AtomicBool>,
+    detached_at: Instant,
+}
+
+/// Detached sessions by identity
+pub type DetachedSessions = Arc<Mutex<HashMap<String, DetachedSession>>>;
+
+/// Remove a client's state, running the disconnect hook if it was still registered.
+///
+/// With `detached` given, a key user's session is kept there for `reattach_grace_secs`.
+async fn remove_client(
+    config: &Config,
+    stats: &ServerStats,
+    clients: &Clients,
+    detached: Option<&DetachedSessions>,
+    id: usize,
+) -> bool {
+    let mut map = clients.lock().await;
+    let Some(state) = map.remove(&id) else {
+        return false;
+    };
+    let active = map.len();
+    drop(map);
+    stats.session_closed();
+    info!(client = id, identity = %state.identity, active, "Session closed");
+
+    if let Some(ref cmd) = config.on_disconnect_cmd {
+        spawn_hook(cmd, "disconnect", &state.identity);
+    }
+    
+    // Kiosk sessions are meant to be thrown away, and IP identities may be shared; a
+    // session busy with a response can't have been quit
+    if let Some(detached) = detached
+        && config.reattach_grace_secs > 0
+        && !config.kiosk
+        && state.identity.starts_with("key_")
+        && !state.chat_session.try_lock().is_ok_and(|s| s.has_ended())
+    {
+        info!(client = id, identity = %state.identity, grace_secs = config.reattach_grace_secs, "Keeping session for a reconnect");
+        detached.lock().await.insert(
+            state.identity.clone(),
+            DetachedSession {
+                chat_session: state.chat_session,
+                input_history: state.input_history,
+                busy: state.busy,
+                detached_at: Instant::now(),
+            },
+        );
+    } else if let Some(cancel) = state.cancel
+        && cancel.send(()).is_ok()
+    {
+        // Nobody will see the answer, so stop the backend request instead of finishing it
+        info!(client = id, "Cancelled the response for a disconnected client");
+    }
+    true
+}
+
+/// Take the session `identity` left behind, unless its grace period is over
+async fn take_detached(config: &Config, detached: &DetachedSessions, identity: &str) -> Option<DetachedSession> {
+    let session = detached.lock().await.remove(identity)?;
+    (session.detached_at.elapsed() < Duration::from_secs(config.reattach_grace_secs)).then_some(session)
+}
+
+/// Drop state left behind by clients whose channel closed without reaching `channel_close`,
+/// and detached sessions whose grace period is over
+async fn reap(config: &Config, stats: &ServerStats, clients: &Clients, detached: &DetachedSessions) {
+    let dead: Vec<usize> = clients
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, state)| state.writer.is_closed())
+        .map(|(id, _)| *id)
+        .collect();
+    for id in dead {
+        if remove_client(config, stats, clients, Some(detached), id).await {
+            warn!(client = id, "Reaped leaked client state");
+        }
+    }
+    let grace = Duration::from_secs(config.reattach_grace_secs);
+    detached.lock().await.retain(|identity, session| {
+        let keep = session.detached_at.elapsed() < grace;
+        if !keep {
+            info!(identity = %identity, "Dropped detached session after the grace period");
+        }
+        keep
+    });
+}
+
+/// Say goodbye to every connected client, close their channels and run disconnect hooks;
+/// every live or detached session logs its end before the server exits
+pub async fn shutdown_clients(config: &Config, stats: &ServerStats, clients: &Clients, detached: &DetachedSessions) {
+    let mut ids = Vec::new();
+    let mut writers = Vec::new();
+    let mut sessions = Vec::new();
+    for (id, state) in clients.lock().await.iter() {
+        ids.push(*id);
+        writers.push(state.writer.clone());
+        sessions.push(state.chat_session.clone());
+    }
+    sessions.extend(detached.lock().await.drain().map(|(_, session)| session.chat_session));
+    
+    for writer in &writers {
+        writer.write("\r\n\x1b[1;33mServer shutting down, goodbye!\x1b[0m\r\n");
+        writer.close();
+    }
+    for id in ids {
+        remove_client(config, stats, clients, None, id).await;
+    }
+    
+    // Cancelled responses release their session; one still busy past the grace period isn't waited for
+    let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE;
+    for session in sessions {
+        if let Ok(session) = tokio::time::timeout_at(deadline, session.lock()).await {
+            session.log_session_end();
+        }
+    }
+    
+    // Writers stop once their close has been sent
+    while writers.iter().any(|w| !w.is_closed()) && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Tell a client why its session is refused, then close the channel
+fn reject_session(config: &Config, session: &Session, channel: ChannelId, message: &str) {
+    let writer = ChannelWriter::spawn(session.handle(), channel, config.color);
+    writer.write(format!("\x1b[1;31m{}\x1b[0m\r\n", message));
+    writer.exit(1);
+}
+
+impl SshServer {
+    /// Periodically drop state for clients whose connection is gone, and detached
+    /// sessions nobody reattached to in time
+    pub fn spawn_reaper(&self) {
+        let config = self.config.clone();
+        let stats = self.stats.clone();
+        let clients = self.clients.clone();
+        let detached = self.detached.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAP_INTERVAL);
+            loop {
+                interval.tick().await;
+                reap(&config, &stats, &clients, &detached).await;
+            }
+        });
+    }
+
+    /// Close sessions that have had no input for `idle_timeout_secs`, warning them first
+    pub fn spawn_idle_sweeper(&self) {
+        if self.config.idle_timeout_secs == 0 {
+            return;
+        }
+        let timeout = Duration::from_secs(self.config.idle_timeout_secs);
+        let warn_after = timeout - IDLE_WARNING.min(timeout / 2);
+        let clients = self.clients.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = Instant::now();
+                for (id, state) in clients.lock().await.iter_mut() {
+                    if state.writer.is_closed() {
+                        continue;
+                    }
+                    // A response in progress counts as activity
+                    if state.busy.load(Ordering::Acquire) {
+                        state.last_activity = now;
+                        continue;
+                    }
+                    
+                    let idle = now.duration_since(state.last_activity);
+                    if idle >= timeout {
+                        info!(client = id, idle_secs = idle.as_secs(), "Disconnecting idle client");
+                        state.writer.write(format!(
+                            "\r\n\x1b[1;33mDisconnected after {} seconds of inactivity.\x1b[0m\r\n",
+                            timeout.as_secs()
+                        ));
+                        state.writer.close();
+                    } else if idle >= warn_after && !state.idle_warned {
+                        state.idle_warned = true;
+                        state.writer.write(format!(
+                            "\r\n\x1b[1;33mNo activity for a while; disconnecting in {} seconds unless you type something.\x1b[0m\r\n{}{}",
+                            (timeout - idle).as_secs(),
+                            state.prompt,
+                            state.input.redraw()
+                        ));
+                    }
+                }
+            }
+        });
+    }
 }
 
 impl russh::server::Server for SshServer {
@@ -30,28 +692,99 @@ impl russh::server::Server for SshServer {
     fn new_client(&mut self, addr: Option<SocketAddr>) -> SshHandler {
         let id = self.id;
         self.id += 1;
-        info!("New client connection from {:?}, assigned id {}", addr, id);
+        let client_ip = addr.map(|a| a.ip().to_string()).unwrap_or_else(|| "127.0.0.1".to_string());
+        info!(client = id, ip = %client_ip, "New client connection");
+        let rate_limited = addr.is_some_and(|a| !self.limiter.allow(a.ip()));
+        if rate_limited {
+            warn!(client = id, ip = %client_ip, "Client exceeded the per-IP connection limit");
+        }
         SshHandler {
             config: self.config.clone(),
+            stats: self.stats.clone(),
             id,
             clients: self.clients.clone(),
-            client_ip: addr.map(|a| a.ip().to_string()).unwrap_or_else(|| "127.0.0.1".to_string()),
+            log_store: self.log_store.clone(),
+            llm_queue: self.llm_queue.clone(),
+            llm: self.llm.clone(),
+            detached: self.detached.clone(),
+            client_ip,
             identity: None,
+            language: None,
+            env: HashMap::new(),
+            auth_failures: 0,
+            rate_limited,
         }
     }
 
     fn handle_session_error(&mut self, error: russh::Error) {
-        error!("Session error: {:?}", error);
+        // Connections dropped on purpose (e.g. too many failed logins) were already logged
+        if matches!(error, russh::Error::Disconnect) {
+            return;
+        }
+        error!(error = ?error, "Session error");
     }
 }
 
 /// Per-connection handler
 pub struct SshHandler {
     config: Arc<Config>,
+    stats: Arc<ServerStats>,
     id: usize,
-    clients: Arc<Mutex<HashMap<usize, ClientState>>>,
+    clients: Clients,
+    log_store: Arc<LogStore>,
+    llm_queue: Arc<RequestQueue>,
+    llm: Arc<LlmClient>,
+    detached: DetachedSessions,
     client_ip: String,
     identity: Option<String>,
+    language: Option<String>,
+    /// Allowlisted variables the client forwarded with `env` requests
+    env: HashMap<String, String>,
+    /// Rejected authentication attempts on this connection
+    auth_failures: u32,
+    /// Connection arrived over the per-IP limit; its session is refused
+    rate_limited: bool,
+}
+
+impl SshHandler {
+    /// Final identity: Use key fingerprint if available, otherwise IP
+    fn final_identity(&self) -> String {
+        self.identity.clone().unwrap_or_else(|| self.client_ip.clone())
+    }
+    
+    /// Reject an authentication attempt, dropping the connection once it has failed too often
+    fn reject_auth(&mut self) -> Result<Auth, russh::Error> {
+        self.auth_failures += 1;
+        let max = self.config.max_auth_attempts;
+        if max > 0 && self.auth_failures >= max {
+            warn!(client = self.id, ip = %self.client_ip, failures = self.auth_failures, "Too many failed authentication attempts, dropping connection");
+            return Err(russh::Error::Disconnect);
+        }
+        if self.auth_failures > 1 {
+            info!(client = self.id, ip = %self.client_ip, failures = self.auth_failures, "Repeated failed authentication");
+        }
+        Ok(Auth::reject())
+    }
+}
+
+impl Drop for SshHandler {
+    // Connections can end without a channel close (e.g. abrupt disconnect)
+    fn drop(&mut self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let config = self.config.clone();
+        let stats = self.stats.clone();
+        let clients = self.clients.clone();
+        let detached = self.detached.clone();
+        let id = self.id;
+
+        runtime.spawn(async move {
+            if remove_client(&config, &stats, &clients, Some(&detached), id).await {
+                info!(client = id, "Cleaned up state for disconnected client");
+            }
+        });
+    }
 }
 
 impl Handler for SshHandler {
@@ -62,21 +795,88 @@ impl Handler for SshHandler {
         channel: Channel<Msg>,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        info!("Channel opened for client {} (IP: {})", self.id, self.client_ip);
+        info!(client = self.id, ip = %self.client_ip, "Channel opened");
         
-        // Final identity: Use key fingerprint if available, otherwise IP
-        let final_identity = self.identity.clone().unwrap_or_else(|| self.client_ip.clone());
+        if self.rate_limited {
+            reject_session(&self.config, session, channel.id(), "Too many connections from your address, try again in a minute.");
+            return Ok(true);
+        }
         
-        let logger = ClientLogger::new(&self.config.logs_dir, final_identity);
-        let chat_session = Arc::new(Mutex::new(ChatSession::new(self.config.clone(), logger)));
+        // Held until the new state is inserted so concurrent opens can't overshoot the cap
+        let mut clients = self.clients.lock().await;
+        // State is kept per connection, so a multiplexed second channel would replace the first
+        if clients.contains_key(&self.id) {
+            warn!(client = self.id, ip = %self.client_ip, "Rejected a second session channel on one connection");
+            reject_session(&self.config, session, channel.id(), "Only one session per connection; open a new connection instead.");
+            return Ok(true);
+        }
+        if self.config.max_sessions > 0 && clients.len() >= self.config.max_sessions {
+            warn!(client = self.id, ip = %self.client_ip, active = clients.len(), "Rejected client: server at capacity");
+            reject_session(&self.config, session, channel.id(), "Server at capacity, try again later.");
+            return Ok(true);
+        }
+        
+        let final_identity = self.final_identity();
+
+        if let Some(ref cmd) = self.config.on_connect_cmd {
+            spawn_hook(cmd, "connect", &final_identity);
+        }
+        
+        let detached = take_detached(&self.config, &self.detached, &final_identity).await;
+        let reattached = detached.is_some();
+        let (chat_session, input_history, busy) = match detached {
+            Some(detached) => {
+                info!(client = self.id, identity = %final_identity, "Reattached to detached session");
+                // Left as it was if a response is still holding the session
+                if let Ok(mut chat_session) = detached.chat_session.try_lock() {
+                    chat_session.set_client_ip(self.client_ip.clone());
+                }
+                (detached.chat_session, detached.input_history, detached.busy)
+            }
+            None => {
+                let logger = ClientLogger::new(&self.config.logs_dir, &final_identity, &self.log_store)
+                    .with_max_log_bytes(self.config.max_log_bytes);
+                let chat_session = Arc::new(Mutex::new(ChatSession::new(
+                    self.config.clone(),
+                    self.stats.clone(),
+                    self.llm_queue.clone(),
+                    self.llm.clone(),
+                    logger,
+                    final_identity.clone(),
+                    self.client_ip.clone(),
+                )));
+                (chat_session, Vec::new(), Arc::new(AtomicBool::new(false)))
+            }
+        };
         
         let state = ClientState {
-            handle: session.handle(),
+            identity: final_identity,
+            channel: channel.id(),
+            writer: ChannelWriter::spawn(session.handle(), channel.id(), self.config.color),
             chat_session,
-            input_buffer: String::new(),
+            input: LineEditor::default(),
+            utf8_pending: Vec::new(),
+            escape_pending: Vec::new(),
+            pasting: false,
+            paste_cr: false,
+            pasted: String::new(),
+            multiline: false,
+            history_index: input_history.len(),
+            input_history,
+            prompt: prompt(&self.config, self.language.as_deref(), false),
+            last_activity: Instant::now(),
+            idle_warned: false,
+            busy,
+            cancel: None,
+            columns: 0,
+            rows: 0,
+            reattached,
         };
         
-        self.clients.lock().await.insert(self.id, state);
+        let identity = state.identity.clone();
+        clients.insert(self.id, state);
+        self.stats.session_opened();
+        info!(client = self.id, identity = %identity, ip = %self.client_ip, active = clients.len(), "Session opened");
         drop(channel);
         Ok(true)
     }
@@ -84,14 +884,17 @@ impl Handler for SshHandler {
     async fn auth_none(&mut self, _user: &str) -> Result<Auth, Self::Error> {
         // partial_success: true tells the client "you are partially logged in, 
         // please provide a key if you have one". This helps identify key-users
-        // while still allowing guest access.
+        // while still allowing guest access. Key-only servers have no guests.
         Ok(Auth::Reject {
-            proceed_with_methods: Some(MethodSet::all()),
-            partial_success: true,
+            proceed_with_methods: Some(self.config.auth_methods()),
+            partial_success: !self.config.requires_key(),
         })
     }
 
     async fn auth_password(&mut self, _user: &str, _password: &str) -> Result<Auth, Self::Error> {
+        if self.config.requires_key() {
+            return self.reject_auth();
+        }
         Ok(Auth::Accept)
     }
 
@@ -103,7 +906,21 @@ impl Handler for SshHandler {
         let hash = hasher.finalize();
         let fingerprint = format!("key_{}", &hex::encode(hash));
         
-        info!("Client authenticated with key {}", fingerprint);
+        if let Some(ref path) = self.config.authorized_keys {
+            match is_authorized(path, key) {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(client = self.id, identity = %fingerprint, ip = %self.client_ip, "Rejected key: not in {}", path.display());
+                    return self.reject_auth();
+                }
+                Err(e) => {
+                    error!(client = self.id, error = %e, "Cannot read authorized keys from {}", path.display());
+                    return Ok(Auth::reject());
+                }
+            }
+        }
+        
+        info!(client = self.id, identity = %fingerprint, ip = %self.client_ip, "Client authenticated with key");
         self.identity = Some(fingerprint);
         Ok(Auth::Accept)
     }
@@ -112,17 +929,104 @@ impl Handler for SshHandler {
         &mut self,
         channel: ChannelId,
         _term: &str,
-        _col_width: u32,
-        _row_height: u32,
+        col_width: u32,
+        row_height: u32,
         _pix_width: u32,
         _pix_height: u32,
         _modes: &[(russh::Pty, u32)],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        if let Some(state) = self.clients.lock().await.get_mut(&self.id) {
+            state.resize(col_width, row_height);
+        }
         session.channel_success(channel)?;
         Ok(())
     }
 
+    async fn window_change_request(
+        &mut self,
+        _channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(state) = self.clients.lock().await.get_mut(&self.id)
+            && state.resize(col_width, row_height)
+        {
+            debug!("Client {} resized terminal to {}x{}", self.id, col_width, row_height);
+            // Terminals may re-lay the input line on resize; redraw it unless a response is being written
+            if !state.busy.load(Ordering::Acquire) {
+                state.writer.write(format!("\r\x1b[K{}{}", state.prompt, state.input.redraw()));
+            }
+        }
+        Ok(())
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if !LOCALE_VARIABLES.contains(&variable_name) {
+            debug!(client = self.id, variable = variable_name, "Refused environment variable");
+            session.channel_failure(channel)?;
+            return Ok(());
+        }
+        self.env.insert(variable_name.to_string(), variable_value.to_string());
+        
+        let locale_language = language_from_env(&self.env);
+        if let Some(lang) = &locale_language
+            && self.config.translations.contains_key(lang)
+        {
+            self.language = Some(lang.clone());
+        }
+        // Cloned out so a reattached session still answering doesn't hold up the client map
+        let chat_session = self.clients.lock().await.get(&self.id).map(|state| state.chat_session.clone());
+        if let Some(chat_session) = chat_session {
+            chat_session.lock().await.set_locale_language(locale_language);
+        }
+        session.channel_success(channel)?;
+        Ok(())
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let input = String::from_utf8_lossy(data).into_owned();
+        if is_transfer_command(&input) {
+            info!(client = self.id, ip = %self.client_ip, command = %input, "Refused file transfer command");
+            session.channel_failure(channel)?;
+            reject_session(&self.config, session, channel, NOT_SUPPORTED);
+            return Ok(());
+        }
+        session.channel_success(channel)?;
+        
+        info!(client = self.id, ip = %self.client_ip, "Client sent a one-shot query");
+        if let Some(state) = self.clients.lock().await.get(&self.id) {
+            tokio::spawn(respond_exec(state.chat_session.clone(), state.writer.clone(), input));
+        }
+        Ok(())
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        info!(client = self.id, subsystem = name, "Refused unsupported subsystem");
+        session.channel_failure(channel)?;
+        reject_session(&self.config, session, channel, NOT_SUPPORTED);
+        Ok(())
+    }
+
     async fn shell_request(
         &mut self,
         channel: ChannelId,
@@ -130,26 +1034,23 @@ impl Handler for SshHandler {
     ) -> Result<(), Self::Error> {
         session.channel_success(channel)?;
         
-        let clients = self.clients.lock().await;
-        if let Some(state) = clients.get(&self.id) {
-            let welcome = state.chat_session.lock().await.welcome_message();
-            let banner = format!(
-                "\r\n\x1b[1;36m\
-                ╔═══════════════════════════════════════════════════════════════════╗\r\n\
-                ║                                                                   ║\r\n\
-                ║   ███████╗███████╗██╗  ██╗██╗     ██╗     ███╗   ███╗             ║\r\n\
-                ║   ██╔════╝██╔════╝██║  ██║██║     ██║     ████╗ ████║             ║\r\n\
-                ║   ███████╗███████╗███████║██║     ██║     ██╔████╔██║             ║\r\n\
-                ║   ╚════██║╚════██║██╔══██║██║     ██║     ██║╚██╔╝██║             ║\r\n\
-                ║   ███████║███████║██║  ██║███████╗███████╗██║ ╚═╝ ██║             ║\r\n\
-                ║   ╚══════╝╚══════╝╚═╝  ╚═╝╚══════╝╚══════╝╚═╝     ╚═╝             ║\r\n\
-                ║                                                                   ║\r\n\
-                ║                SSH LLM Chat Server                                ║\r\n\
-                ╚═══════════════════════════════════════════════════════════════════╝\x1b[0m\r\n\
-                {}\r\n\r\n\x1b[1;32mYou: \x1b[0m",
-                welcome
-            );
-            session.data(channel, CryptoVec::from(banner.as_bytes()))?;
+        let mut clients = self.clients.lock().await;
+        if let Some(state) = clients.get_mut(&self.id) {
+            state.prompt = prompt(&self.config, self.language.as_deref(), state.multiline);
+            let welcome = if state.reattached {
+                // A response still running from the old connection holds the session
+                match state.chat_session.try_lock() {
+                    Ok(chat_session) => chat_session.reattach_message(),
+                    Err(_) => "Reconnected. Your last message is still being answered; its reply will be in /export.".to_string(),
+                }
+            } else {
+                state.chat_session.lock().await.welcome_message(self.language.as_deref())
+            };
+            let welcome = wrap(&welcome, state.columns, 0).replace('\n', "\r\n");
+            let banner = format!("\r\n{}\r\n{}\r\n\r\n{}", banner(&self.config), welcome, state.prompt);
+            state.writer.write(banner);
+            state.writer.write(BRACKETED_PASTE_ON);
+            state.writer.on_close(BRACKETED_PASTE_OFF);
         }
         
         Ok(())
@@ -157,73 +1058,191 @@ impl Handler for SshHandler {
 
     async fn data(
         &mut self,
-        channel: ChannelId,
+        _channel: ChannelId,
         data: &[u8],
-        session: &mut Session,
+        _session: &mut Session,
     ) -> Result<(), Self::Error> {
         let mut clients = self.clients.lock().await;
         
         if let Some(state) = clients.get_mut(&self.id) {
+            state.last_activity = Instant::now();
+            state.idle_warned = false;
+            let writer = state.writer.clone();
             for &byte in data {
+                // An ASCII byte can't continue a multibyte character
+                if byte < 0x80 {
+                    state.utf8_pending.clear();
+                }
+                
+                if byte == 0x1b || !state.escape_pending.is_empty() {
+                    state.escape_pending.push(byte);
+                    match parse_escape(&state.escape_pending) {
+                        Escape::Incomplete => continue,
+                        Escape::Key(key @ (Key::Up | Key::Down)) => {
+                            if let Some(line) = state.recall_history(matches!(key, Key::Up)) {
+                                let echo = state.set_input(line);
+                                writer.write(format!("\r\x1b[K{}{}", prompt(&self.config, self.language.as_deref(), state.multiline), echo));
+                            }
+                        }
+                        Escape::Key(Key::Left) => writer.write(state.input.move_left()),
+                        Escape::Key(Key::Right) => writer.write(state.input.move_right()),
+                        Escape::Key(Key::Home) => writer.write(state.input.home()),
+                        Escape::Key(Key::End) => writer.write(state.input.end()),
+                        Escape::Key(Key::Delete) => writer.write(state.input.delete()),
+                        Escape::Key(Key::PasteStart) => state.pasting = true,
+                        Escape::Key(Key::PasteEnd) => {
+                            state.pasting = false;
+                            state.paste_cr = false;
+                        }
+                        Escape::Unknown => {}
+                    }
+                    state.escape_pending.clear();
+                    continue;
+                }
+                
+                // Pasted line breaks and tabs are text; other control bytes are dropped
+                if state.pasting && byte < 32 {
+                    writer.write(state.paste_control(byte));
+                    continue;
+                }
+                state.paste_cr = false;
+                
                 match byte {
-                    // Enter key
-                    b'\r' | b'\n' => {
-                        let input = std::mem::take(&mut state.input_buffer);
+                    // Enter key, or Ctrl+D to send a multi-line message
+                    b'\r' | b'\n' | 4 if byte != 4 || (state.multiline && !state.pasted.is_empty()) => {
+                        if byte != 4 && state.continue_multiline() {
+                            writer.write("\r\n");
+                            continue;
+                        }
+                        
+                        // One response at a time keeps output and history in order
+                        if state.busy.load(Ordering::Acquire) {
+                            let input = state.take_input();
+                            state.push_history(input.trim());
+                            writer.write(
+                                "\r\n\x1b[1;33mPlease wait for the current response (Ctrl+C cancels it, Up gets your message back).\x1b[0m\r\n",
+                            );
+                            continue;
+                        }
+                        
+                        let input = state.take_input();
                         let input_trimmed = input.trim().to_string();
                         
                         // Echo newline immediately
-                        session.data(channel, CryptoVec::from("\r\n".as_bytes()))?;
+                        writer.write("\r\n");
 
+                        state.push_history(&input_trimmed);
+                        
+                        // Handled here: the mode belongs to the terminal, not the chat session
+                        if input_trimmed == "/multiline" {
+                            state.multiline = !state.multiline;
+                            state.prompt = prompt(&self.config, self.language.as_deref(), state.multiline);
+                            let note = if state.multiline {
+                                "Multi-line mode on: Enter starts a new line; send with a lone . or Ctrl+D. /multiline turns it off."
+                            } else {
+                                "Multi-line mode off."
+                            };
+                            writer.write(format!("{}{}\r\n\r\n{}", AI_LABEL, note, state.prompt));
+                            continue;
+                        }
+                        
                         if !input_trimmed.is_empty() {
-                            let handle = state.handle.clone();
+                            // No locale from the client: guess from the first message
+                            if self.language.is_none()
+                                && let Some(lang) = detect_language(&input_trimmed)
+                                && self.config.translations.contains_key(lang)
+                            {
+                                self.language = Some(lang.to_string());
+                            }
+                            
+                            let writer = writer.clone();
                             let chat_session = state.chat_session.clone();
+                            let prompt = prompt(&self.config, self.language.as_deref(), state.multiline);
+                            state.prompt = prompt.clone();
                             
                             // Send thinking indicator immediately to the client
-                            session.data(channel, CryptoVec::from("\x1b[1;36mAI:\x1b[0m (thinking...)\r".as_bytes()))?;
+                            writer.write(thinking_line(0));
                             
                             // Spawn background task for LLM call so we can return and the packet gets sent
-                            tokio::spawn(async move {
-                                let mut session_lock = chat_session.lock().await;
-                                let result = session_lock.process_input(&input_trimmed).await;
-                                drop(session_lock);
-
-                                match result {
-                                    Ok(response) => {
-                                        let response = response.replace('\n', "\r\n");
-                                        let output = format!("\x1b[1;36mAI:\x1b[0m {}\r\n\r\n\x1b[1;32mYou: \x1b[0m", response);
-                                        let _ = handle.data(channel, CryptoVec::from(output.as_bytes())).await;
-                                    }
-                                    Err(e) if e == "quit" => {
-                                        let _ = handle.data(channel, CryptoVec::from("\r\nGoodbye!\r\n".as_bytes())).await;
-                                        let _ = handle.close(channel).await;
-                                    }
-                                    Err(e) => {
-                                        let output = format!("\x1b[1;31mError: {}\x1b[0m\r\n\r\n\x1b[1;32mYou: \x1b[0m", e);
-                                        let _ = handle.data(channel, CryptoVec::from(output.as_bytes())).await;
-                                    }
-                                }
-                            });
+                            state.busy.store(true, Ordering::Release);
+                            let (cancel, cancelled) = oneshot::channel();
+                            state.cancel = Some(cancel);
+                            tokio::spawn(respond(chat_session, writer, state.busy.clone(), cancelled, input_trimmed, prompt, state.columns));
                         } else {
-                            session.data(channel, CryptoVec::from("\x1b[1;32mYou: \x1b[0m".as_bytes()))?;
+                            writer.write(prompt(&self.config, self.language.as_deref(), state.multiline));
                         }
                     }
                     // Backspace
                     127 | 8 => {
-                        if !state.input_buffer.is_empty() {
-                            state.input_buffer.pop();
-                            session.data(channel, CryptoVec::from("\x08 \x08".as_bytes()))?;
+                        writer.write(state.input.backspace());
+                    }
+                    // Ctrl+D: quit on an empty line, otherwise delete forward like a shell
+                    4 => {
+                        if state.input.is_empty() {
+                            if let Ok(mut chat_session) = state.chat_session.try_lock() {
+                                chat_session.end();
+                            }
+                            writer.write("\r\nGoodbye!\r\n");
+                            writer.close();
+                            return Ok(());
+                        }
+                        writer.write(state.input.delete());
+                    }
+                    // Tab
+                    9 => {
+                        writer.write(state.complete_command(&self.config));
+                    }
+                    // Ctrl+L: clear the screen only; the conversation is kept
+                    12 => {
+                        writer.write("\x1b[2J\x1b[H");
+                        if !state.busy.load(Ordering::Acquire) {
+                            writer.write(format!("{}{}", state.prompt, state.input.redraw()));
                         }
                     }
-                    // Ctrl+C
+                    // Ctrl+U; also drops the lines of a multi-line paste
+                    21 => {
+                        writer.write(state.input.clear());
+                        if !state.pasted.is_empty() {
+                            state.pasted.clear();
+                            writer.write(format!("\r\n{}", state.prompt));
+                        }
+                    }
+                    // Ctrl+W
+                    23 => {
+                        writer.write(state.input.delete_word());
+                    }
+                    // Ctrl+C: cancel the response being generated, or disconnect when idle
                     3 => {
-                        session.data(channel, CryptoVec::from("\r\n^C\r\n".as_bytes()))?;
-                        session.close(channel)?;
+                        if state.busy.load(Ordering::Acquire)
+                            && let Some(cancel) = state.cancel.take()
+                        {
+                            let _ = cancel.send(());
+                            continue;
+                        }
+                        if let Ok(mut chat_session) = state.chat_session.try_lock() {
+                            chat_session.end();
+                        }
+                        writer.write("\r\n^C\r\n");
+                        writer.close();
                         return Ok(());
                     }
                     // Regular printable characters
                     32..=126 => {
-                        state.input_buffer.push(byte as char);
-                        session.data(channel, CryptoVec::from(std::slice::from_ref(&byte)))?;
+                        writer.write(state.input.insert(&(byte as char).to_string()));
+                    }
+                    // Multibyte UTF-8, possibly split across several data callbacks
+                    0x80..=0xFF => {
+                        state.utf8_pending.push(byte);
+                        match std::str::from_utf8(&state.utf8_pending) {
+                            Ok(text) => {
+                                writer.write(state.input.insert(text));
+                                state.utf8_pending.clear();
+                            }
+                            // Incomplete sequence: wait for more bytes
+                            Err(e) if e.error_len().is_none() => {}
+                            // Invalid sequence: drop it
+                            Err(_) => state.utf8_pending.clear(),
+                        }
                     }
                     _ => {}
                 }
@@ -238,8 +1257,272 @@ impl Handler for SshHandler {
         channel: ChannelId,
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
-        info!("Channel {:?} closed for client {}", channel, self.id);
-        self.clients.lock().await.remove(&self.id);
+        info!(client = self.id, channel = ?channel, "Channel closed");
+        let ours = self.clients.lock().await.get(&self.id).is_some_and(|state| state.channel == channel);
+        if ours {
+            remove_client(&self.config, &self.stats, &self.clients, Some(&self.detached), self.id).await;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{config, openai_answer, MockBackend, SilentBackend, TempDir};
+    use russh::client;
+    use russh::keys::signature::rand_core::OsRng;
+    use russh::keys::{Algorithm, PrivateKey};
+    use russh::server::Server as _;
+    use russh::ChannelMsg;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+    
+    /// How long a test waits for output or cleanup before failing
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+    
+    /// Wait for `condition`, failing after `TEST_TIMEOUT`
+    async fn eventually(what: &str, condition: impl Fn() -> bool) {
+        let deadline = tokio::time::Instant::now() + TEST_TIMEOUT;
+        while !condition() {
+            assert!(tokio::time::Instant::now() < deadline, "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+    
+    /// Client trusting any host key
+    struct TestClient;
+    
+    impl client::Handler for TestClient {
+        type Error = russh::Error;
+        
+        async fn check_server_key(&mut self, _key: &PublicKey) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+    
+    /// A server listening on a loopback port, with its client map to look into
+    struct TestServer {
+        addr: SocketAddr,
+        config: Arc<Config>,
+        stats: Arc<ServerStats>,
+        clients: Clients,
+        detached: DetachedSessions,
+        _logs: TempDir,
+    }
+    
+    impl TestServer {
+        async fn start(config: Config) -> Self {
+            let logs = TempDir::new();
+            let config = Arc::new(Config { logs_dir: logs.path().to_path_buf(), ..config });
+            let stats = Arc::new(ServerStats::new());
+            let clients: Clients = Default::default();
+            let detached: DetachedSessions = Default::default();
+            let mut server = SshServer {
+                config: config.clone(),
+                stats: stats.clone(),
+                id: 0,
+                clients: clients.clone(),
+                limiter: Arc::new(ConnectionLimiter::new(config.max_conns_per_ip)),
+                llm_queue: Arc::new(RequestQueue::new(config.max_concurrent_llm)),
+                llm: Arc::new(LlmClient::new(config.clone(), None).unwrap()),
+                log_store: Default::default(),
+                detached: detached.clone(),
+            };
+            let ssh_config = Arc::new(russh::server::Config {
+                keys: vec![PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap()],
+                methods: config.auth_methods(),
+                auth_rejection_time: Duration::ZERO,
+                ..Default::default()
+            });
+            let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            tokio::spawn(async move {
+                let _ = server.run_on_socket(ssh_config, &socket).await;
+            });
+            Self { addr, config, stats, clients, detached, _logs: logs }
+        }
+        
+        /// Wait until `count` clients have state
+        async fn wait_for_clients(&self, count: usize) {
+            eventually("the client count", || self.clients.try_lock().is_ok_and(|clients| clients.len() == count)).await;
+        }
+    }
+    
+    /// An interactive session, connected through a pipe the test can cut
+    struct Shell {
+        channel: russh::Channel<client::Msg>,
+        output: String,
+        pipe: JoinHandle<()>,
+        handle: client::Handle<TestClient>,
+    }
+    
+    impl Shell {
+        /// Log in with a password (an IP identity) and ask for a shell
+        async fn open(server: &TestServer) -> Self {
+            let mut tcp = TcpStream::connect(server.addr).await.unwrap();
+            let (stream, mut far_end) = tokio::io::duplex(64 * 1024);
+            let pipe = tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut far_end, &mut tcp).await;
+            });
+            let mut handle = client::connect_stream(Arc::new(client::Config::default()), stream, TestClient)
+                .await
+                .unwrap();
+            assert!(handle.authenticate_password("test", "test").await.unwrap().success());
+            let channel = handle.channel_open_session().await.unwrap();
+            channel.request_pty(false, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+            channel.request_shell(false).await.unwrap();
+            Self { channel, output: String::new(), pipe, handle }
+        }
+        
+        async fn send(&self, text: &str) {
+            self.channel.data(text.as_bytes()).await.unwrap();
+        }
+        
+        /// Read until the output contains `needle`; false if the channel closed first
+        async fn read_until(&mut self, needle: &str) -> bool {
+            let read = async {
+                while !self.output.contains(needle) {
+                    match self.channel.wait().await {
+                        Some(ChannelMsg::Data { data }) => self.output.push_str(&String::from_utf8_lossy(&data)),
+                        Some(ChannelMsg::Eof | ChannelMsg::Close) | None => return false,
+                        Some(_) => {}
+                    }
+                }
+                true
+            };
+            tokio::time::timeout(TEST_TIMEOUT, read).await.expect("timed out waiting for output")
+        }
+        
+        /// Drop the TCP connection without closing the channel or the SSH session
+        fn cut(self) {
+            self.pipe.abort();
+        }
+    }
+    
+    #[tokio::test]
+    async fn sessions_over_the_cap_are_refused() {
+        let server = TestServer::start(Config { max_sessions: 1, ..config("http://127.0.0.1:9") }).await;
+        
+        let mut first = Shell::open(&server).await;
+        assert!(first.read_until("You: ").await);
+        
+        let mut second = Shell::open(&server).await;
+        assert!(!second.read_until("You: ").await);
+        assert!(second.output.contains("Server at capacity"), "{:?}", second.output);
+        assert_eq!(server.clients.lock().await.len(), 1);
+        
+        // A slot frees up once the first session leaves
+        first.cut();
+        server.wait_for_clients(0).await;
+        let mut third = Shell::open(&server).await;
+        assert!(third.read_until("You: ").await);
+    }
+    
+    #[tokio::test]
+    async fn input_while_a_response_is_running_is_not_sent() {
+        let backend = MockBackend::start_delayed(Duration::from_millis(500), vec![openai_answer("hello there")]).await;
+        let server = TestServer::start(config(&backend.url)).await;
+        let mut shell = Shell::open(&server).await;
+        assert!(shell.read_until("You: ").await);
+        
+        // Both lines in one packet, then another while the first is still waiting on the backend
+        shell.send("hi\ragain\r").await;
+        assert!(shell.read_until("Please wait for the current response").await);
+        eventually("the first request", || backend.requests() == 1).await;
+        shell.output.clear();
+        shell.send("more\r").await;
+        assert!(shell.read_until("Please wait for the current response").await);
+        assert!(shell.read_until("hello there").await);
+        
+        let chat_session = server.clients.lock().await.values().next().unwrap().chat_session.clone();
+        let chat = chat_session.lock().await;
+        let turns: Vec<_> = chat.messages().iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+        assert_eq!(turns, [("user", "hi"), ("assistant", "hello there")]);
+        assert_eq!(backend.requests(), 1);
+    }
+    
+    #[tokio::test]
+    async fn abrupt_disconnect_removes_client_state() {
+        let server = TestServer::start(config("http://127.0.0.1:9")).await;
+        let mut shell = Shell::open(&server).await;
+        assert!(shell.read_until("You: ").await);
+        assert_eq!(server.stats.active_sessions(), 1);
+        
+        // No channel close, no SSH disconnect: the TCP connection just goes away
+        shell.cut();
+        server.wait_for_clients(0).await;
+        assert_eq!(server.stats.active_sessions(), 0);
+    }
+    
+    #[tokio::test]
+    async fn reaper_removes_state_whose_close_was_missed() {
+        let server = TestServer::start(config("http://127.0.0.1:9")).await;
+        let mut shell = Shell::open(&server).await;
+        assert!(shell.read_until("You: ").await);
+        
+        // Filed under an id no handler owns, as if channel_close had never arrived for it
+        let writer = {
+            let mut clients = server.clients.lock().await;
+            let id = *clients.keys().next().unwrap();
+            let state = clients.remove(&id).unwrap();
+            let writer = state.writer.clone();
+            clients.insert(usize::MAX, state);
+            writer
+        };
+        writer.close();
+        // Read to the channel close
+        assert!(!shell.read_until("\0").await);
+        eventually("the writer to stop", || writer.is_closed()).await;
+        assert_eq!(server.clients.lock().await.len(), 1);
+        
+        reap(&server.config, &server.stats, &server.clients, &server.detached).await;
+        assert!(server.clients.lock().await.is_empty());
+        assert_eq!(server.stats.active_sessions(), 0);
+    }
+    
+    #[tokio::test]
+    async fn disconnecting_during_a_response_cancels_the_backend_request() {
+        let backend = SilentBackend::start().await;
+        let server = TestServer::start(Config { request_timeout_secs: 60, ..config(&backend.url) }).await;
+        let mut shell = Shell::open(&server).await;
+        assert!(shell.read_until("You: ").await);
+        
+        shell.send("hi\r").await;
+        eventually("the request", || backend.requests() == 1).await;
+        assert_eq!(backend.hangups(), 0);
+        
+        shell.cut();
+        eventually("the request to be dropped", || backend.hangups() == 1).await;
+        server.wait_for_clients(0).await;
+    }
+    
+    #[tokio::test]
+    async fn second_session_channel_on_a_connection_is_refused() {
+        let server = TestServer::start(config("http://127.0.0.1:9")).await;
+        let mut shell = Shell::open(&server).await;
+        assert!(shell.read_until("You: ").await);
+        
+        let mut second = shell.handle.channel_open_session().await.unwrap();
+        let mut refusal = String::new();
+        let read = async {
+            while let Some(msg) = second.wait().await {
+                match msg {
+                    ChannelMsg::Data { data } => refusal.push_str(&String::from_utf8_lossy(&data)),
+                    ChannelMsg::Close => break,
+                    _ => {}
+                }
+            }
+        };
+        tokio::time::timeout(TEST_TIMEOUT, read).await.expect("timed out waiting for the refusal");
+        assert!(refusal.contains("Only one session per connection"), "{:?}", refusal);
+        assert_eq!(server.stats.active_sessions(), 1);
+        
+        // The first session is untouched
+        shell.send("/help\r").await;
+        assert!(shell.read_until("Commands:").await);
+        shell.cut();
+        server.wait_for_clients(0).await;
+        assert_eq!(server.stats.active_sessions(), 0);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::logger::UserSummary;
+use chrono::{NaiveDate, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    identity TEXT PRIMARY KEY,
+    name TEXT,
+    sessions INTEGER NOT NULL DEFAULT 0,
+    messages INTEGER NOT NULL DEFAULT 0,
+    first_seen TEXT,
+    last_seen TEXT,
+    stream INTEGER,
+    language TEXT,
+    messages_today INTEGER NOT NULL DEFAULT 0,
+    messages_day TEXT
+);
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    identity TEXT NOT NULL,
+    session TEXT,
+    ts TEXT NOT NULL,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_identity_ts ON messages (identity, ts);
+CREATE TABLE IF NOT EXISTS snapshots (
+    identity TEXT NOT NULL,
+    label TEXT NOT NULL,
+    saved TEXT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (identity, label)
+);
+";
+
+fn io_error(e: rusqlite::Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+/// `YYYY-MM-DD` prefix of a stored timestamp, for matching whole days
+fn day(date: NaiveDate) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+/// Chat logs and user summaries for every identity in one SQLite file
+pub struct LogDatabase {
+    conn: Mutex<Connection>,
+}
+
+impl LogDatabase {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn load_summary(conn: &Connection, identity: &str) -> rusqlite::Result<UserSummary> {
+        let summary = conn
+            .query_row(
+                "SELECT name, sessions, messages, first_seen, last_seen, stream, messages_today, messages_day, language
+                 FROM users WHERE identity = ?1",
+                params![identity],
+                |row| {
+                    Ok(UserSummary {
+                        name: row.get(0)?,
+                        total_sessions: row.get(1)?,
+                        total_messages: row.get::<_, i64>(2)?.max(0) as u64,
+                        first_seen: row.get::<_, Option<String>>(3)?.and_then(|t| t.parse().ok()),
+                        last_seen: row.get::<_, Option<String>>(4)?.and_then(|t| t.parse().ok()),
+                        stream: row.get(5)?,
+                        messages_today: row.get(6)?,
+                        messages_day: row.get::<_, Option<String>>(7)?.and_then(|d| d.parse().ok()),
+                        language: row.get(8)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(summary.unwrap_or_default())
+    }
+
+    pub fn read_summary(&self, identity: &str) -> std::io::Result<Option<UserSummary>> {
+        let conn = self.conn();
+        let exists: bool = conn
+            .query_row("SELECT EXISTS(SELECT 1 FROM users WHERE identity = ?1)", params![identity], |row| row.get(0))
+            .map_err(io_error)?;
+        if !exists {
+            return Ok(None);
+        }
+        Self::load_summary(&conn, identity).map(Some).map_err(io_error)
+    }
+
+    /// Apply a change to the user's row and write it back; `last_seen` is stored as now
+    pub fn update_summary(&self, identity: &str, change: impl FnOnce(&mut UserSummary)) -> std::io::Result<UserSummary> {
+        let conn = self.conn();
+        let mut summary = Self::load_summary(&conn, identity).map_err(io_error)?;
+        change(&mut summary);
+        conn.execute(
+            "INSERT INTO users (identity, name, sessions, messages, first_seen, last_seen, stream, messages_today, messages_day, language)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+             ON CONFLICT (identity) DO UPDATE SET name = ?2, sessions = ?3, messages = ?4,
+                 first_seen = ?5, last_seen = ?6, stream = ?7, messages_today = ?8, messages_day = ?9, language = ?10",
+            params![
+                identity,
+                summary.name,
+                summary.total_sessions,
+                summary.total_messages as i64,
+                summary.first_seen.map(|t| t.to_rfc3339()),
+                Utc::now().to_rfc3339(),
+                summary.stream,
+                summary.messages_today,
+                summary.messages_day.map(day),
+                summary.language,
+            ],
+        )
+        .map_err(io_error)?;
+        Ok(summary)
+    }
+
+    pub fn delete_summary(&self, identity: &str) -> std::io::Result<()> {
+        self.conn()
+            .execute("DELETE FROM users WHERE identity = ?1", params![identity])
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    pub fn log(&self, identity: &str, session: Option<&str>, time: &str, role: &str, content: &str) -> std::io::Result<()> {
+        self.conn()
+            .execute(
+                "INSERT INTO messages (identity, session, ts, role, content) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![identity, session, time, role, content],
+            )
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    /// The last `limit` messages logged on `date`, oldest first, with the `undo` markers
+    /// between them; session markers don't count towards the limit
+    pub fn history(&self, identity: &str, date: NaiveDate, limit: usize) -> std::io::Result<Vec<(String, String)>> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare(
+                "SELECT role, content FROM messages WHERE identity = ?1 AND substr(ts, 1, 10) = ?2
+                 AND (role IN ('user', 'assistant') OR (role = 'event' AND content = 'undo'))
+                 ORDER BY id DESC LIMIT ?3",
+            )
+            .map_err(io_error)?;
+        let mut history = statement
+            .query_map(params![identity, day(date), limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(io_error)?;
+        history.reverse();
+        Ok(history)
+    }
+
+    /// The `limit` newest messages whose lowercased content contains `query`, as (ts, role, content).
+    /// Matched in Rust, newest first, since SQLite's `lower()` only folds ASCII
+    pub fn search(&self, identity: &str, query: &str, limit: usize) -> std::io::Result<Vec<(String, String, String)>> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare("SELECT ts, role, content FROM messages WHERE identity = ?1 AND role != 'event' ORDER BY id DESC")
+            .map_err(io_error)?;
+        let rows = statement
+            .query_map(params![identity], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?)))
+            .map_err(io_error)?;
+        let mut hits = Vec::new();
+        for row in rows {
+            if hits.len() >= limit {
+                break;
+            }
+            let row = row.map_err(io_error)?;
+            if row.2.to_lowercase().contains(query) {
+                hits.push(row);
+            }
+        }
+        Ok(hits)
+    }
+
+    /// Days the user has logged messages on, oldest first
+    pub fn log_days(&self, identity: &str) -> std::io::Result<Vec<String>> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare("SELECT DISTINCT substr(ts, 1, 10) AS day FROM messages WHERE identity = ?1 ORDER BY day")
+            .map_err(io_error)?;
+        statement
+            .query_map(params![identity], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(io_error)
+    }
+
+    pub fn delete_day(&self, identity: &str, date: NaiveDate) -> std::io::Result<usize> {
+        self.conn()
+            .execute(
+                "DELETE FROM messages WHERE identity = ?1 AND substr(ts, 1, 10) = ?2",
+                params![identity, day(date)],
+            )
+            .map_err(io_error)
+    }
+
+    pub fn delete_before(&self, identity: &str, date: NaiveDate) -> std::io::Result<usize> {
+        self.conn()
+            .execute(
+                "DELETE FROM messages WHERE identity = ?1 AND substr(ts, 1, 10) < ?2",
+                params![identity, day(date)],
+            )
+            .map_err(io_error)
+    }
+
+    pub fn delete_messages(&self, identity: &str) -> std::io::Result<usize> {
+        self.conn()
+            .execute("DELETE FROM messages WHERE identity = ?1", params![identity])
+            .map_err(io_error)
+    }
+
+    pub fn save_snapshot(&self, identity: &str, label: &str, data: &str) -> std::io::Result<()> {
+        self.conn()
+            .execute(
+                "INSERT INTO snapshots (identity, label, saved, data) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (identity, label) DO UPDATE SET saved = ?3, data = ?4",
+                params![identity, label, Utc::now().to_rfc3339(), data],
+            )
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    pub fn load_snapshot(&self, identity: &str, label: &str) -> std::io::Result<Option<String>> {
+        self.conn()
+            .query_row(
+                "SELECT data FROM snapshots WHERE identity = ?1 AND label = ?2",
+                params![identity, label],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(io_error)
+    }
+
+    /// Labels of the user's snapshots, alphabetically
+    pub fn snapshot_labels(&self, identity: &str) -> std::io::Result<Vec<String>> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare("SELECT label FROM snapshots WHERE identity = ?1 ORDER BY label")
+            .map_err(io_error)?;
+        statement
+            .query_map(params![identity], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(io_error)
+    }
+
+    pub fn delete_snapshots(&self, identity: &str) -> std::io::Result<usize> {
+        self.conn()
+            .execute("DELETE FROM snapshots WHERE identity = ?1", params![identity])
+            .map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use chrono::Local;
+
+    #[test]
+    fn history_limit_counts_only_messages() {
+        let dir = TempDir::new();
+        let db = LogDatabase::open(&dir.path().join("sshllm.db")).unwrap();
+        let now = Local::now().to_rfc3339();
+        for (role, content) in [
+            ("event", "session_start"),
+            ("user", "one"),
+            ("assistant", "two"),
+            ("event", "session_start"),
+            ("event", "session_start"),
+            ("user", "three"),
+            ("assistant", "four"),
+        ] {
+            db.log("key_a", None, &now, role, content).unwrap();
+        }
+
+        let history = db.history("key_a", Local::now().date_naive(), 4).unwrap();
+        let contents: Vec<&str> = history.iter().map(|(_, content)| content.as_str()).collect();
+        assert_eq!(contents, ["one", "two", "three", "four"]);
+    }
+
+    #[test]
+    fn search_folds_non_ascii_case() {
+        let dir = TempDir::new();
+        let db = LogDatabase::open(&dir.path().join("sshllm.db")).unwrap();
+        let now = Local::now().to_rfc3339();
+        db.log("key_a", None, &now, "user", "Jadę do Łodzi").unwrap();
+        db.log("key_a", None, &now, "assistant", "ÄPFEL sind rund").unwrap();
+        db.log("key_a", None, &now, "event", "session_start").unwrap();
+
+        let hits = db.search("key_a", &"łodzi".to_lowercase(), 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].2, "Jadę do Łodzi");
+        let hits = db.search("key_a", &"äpfel".to_lowercase(), 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(db.search("key_a", "", 1).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds in seconds of the LLM request duration histogram buckets
+pub const LLM_DURATION_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Process-wide counters shared by every session
+pub struct ServerStats {
+    started_at: Instant,
+    active_sessions: AtomicU64,
+    total_sessions: AtomicU64,
+    backend_errors: AtomicU64,
+    messages: AtomicU64,
+    /// Requests per duration bucket (not cumulative), the last one for anything slower
+    llm_buckets: [AtomicU64; LLM_DURATION_BUCKETS.len() + 1],
+    llm_duration_micros: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            active_sessions: AtomicU64::new(0),
+            total_sessions: AtomicU64::new(0),
+            backend_errors: AtomicU64::new(0),
+            messages: AtomicU64::new(0),
+            llm_buckets: Default::default(),
+            llm_duration_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn session_opened(&self) {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        self.total_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_closed(&self) {
+        let _ = self.active_sessions.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    pub fn backend_error(&self) {
+        self.backend_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_processed(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn llm_request_finished(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = LLM_DURATION_BUCKETS.iter().position(|&bound| secs <= bound).unwrap_or(LLM_DURATION_BUCKETS.len());
+        self.llm_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.llm_duration_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    pub fn active_sessions(&self) -> u64 {
+        self.active_sessions.load(Ordering::Relaxed)
+    }
+
+    pub fn total_sessions(&self) -> u64 {
+        self.total_sessions.load(Ordering::Relaxed)
+    }
+
+    pub fn backend_errors(&self) -> u64 {
+        self.backend_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    /// Cumulative request counts for each of `LLM_DURATION_BUCKETS` plus `+Inf`, and the total seconds spent
+    pub fn llm_durations(&self) -> (Vec<u64>, f64) {
+        let mut total = 0;
+        let counts = self
+            .llm_buckets
+            .iter()
+            .map(|bucket| {
+                total += bucket.load(Ordering::Relaxed);
+                total
+            })
+            .collect();
+        (counts, self.llm_duration_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0)
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Format a number of seconds as e.g. `2d 3h 4m 5s`
+pub fn format_duration(secs: u64) -> String {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (hours, rem) = (rem / 3_600, rem % 3_600);
+    let (mins, secs) = (rem / 60, rem % 60);
+
+    if days > 0 {
+        format!("{}d {}h {}m {}s", days, hours, mins, secs)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
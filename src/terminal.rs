@@ -0,0 +1,168 @@
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Remove escape sequences and control characters (except newline and tab)
+/// so untrusted text can't move the cursor or restyle the user's terminal
+pub fn strip_control_sequences(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: parameters and intermediates, then one final byte
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC/DCS/APC/PM/SOS: string terminated by BEL or ESC \
+                Some(']' | 'P' | '_' | '^' | 'X') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' {
+                            break;
+                        }
+                        if c == '\x1b' && chars.peek() == Some(&'\\') {
+                            chars.next();
+                            break;
+                        }
+                    }
+                }
+                // Two-character escapes
+                _ => {}
+            },
+            '\n' | '\t' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Remove SGR (color/style) sequences like `\x1b[1;32m`, keeping cursor movement intact
+pub fn strip_colors(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("\x1b[") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let params = after.find(|c: char| !(c.is_ascii_digit() || c == ';')).unwrap_or(after.len());
+        if after[params..].starts_with('m') {
+            rest = &after[params + 1..];
+        } else {
+            out.push_str(&rest[start..start + 2]);
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Reflows text to a terminal width as it arrives, breaking at spaces.
+///
+/// Existing newlines are kept and fenced code blocks pass through unwrapped;
+/// a width of 0 disables wrapping.
+pub struct WordWrapper {
+    width: usize,
+    /// Columns already used on the current terminal line
+    column: usize,
+    /// Text of the current line so far, to spot code fences
+    line: String,
+    /// Word waiting for a space or newline before it can be placed
+    word: String,
+    in_code: bool,
+}
+
+impl WordWrapper {
+    /// Start wrapping at `column`, e.g. after a label already on the line
+    pub fn new(width: usize, column: usize) -> Self {
+        Self {
+            width,
+            column,
+            line: String::new(),
+            word: String::new(),
+            in_code: false,
+        }
+    }
+
+    /// Wrap the next piece of text; part of the last word may be held back
+    pub fn push(&mut self, text: &str) -> String {
+        if self.width == 0 {
+            return text.to_string();
+        }
+
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            if c == '\n' {
+                self.flush_word(&mut out);
+                out.push('\n');
+                self.column = 0;
+                if self.line.trim_start().starts_with("```") {
+                    self.in_code = !self.in_code;
+                }
+                self.line.clear();
+                continue;
+            }
+            self.line.push(c);
+
+            if self.in_code || self.line.trim_start().starts_with("```") {
+                self.flush_word(&mut out);
+                out.push(c);
+                self.column += c.width().unwrap_or(0);
+            } else if c == ' ' || c == '\t' {
+                self.flush_word(&mut out);
+                let next = if c == '\t' { (self.column / 8 + 1) * 8 } else { self.column + 1 };
+                if next > self.width {
+                    out.push('\n');
+                    self.column = 0;
+                } else {
+                    out.push(c);
+                    self.column = next;
+                }
+            } else {
+                self.word.push(c);
+            }
+        }
+        out
+    }
+
+    /// Emit whatever is still held back
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        self.flush_word(&mut out);
+        out
+    }
+
+    /// Place the pending word, moving it to a new line if it doesn't fit
+    fn flush_word(&mut self, out: &mut String) {
+        if self.word.is_empty() {
+            return;
+        }
+        if self.column > 0 && self.column + self.word.width() > self.width {
+            out.push('\n');
+            self.column = 0;
+        }
+
+        // Words longer than a whole line are split wherever they hit the edge
+        for c in self.word.drain(..) {
+            let width = c.width().unwrap_or(0);
+            if self.column > 0 && self.column + width > self.width {
+                out.push('\n');
+                self.column = 0;
+            }
+            out.push(c);
+            self.column += width;
+        }
+    }
+}
+
+/// Wrap a complete text to `width` columns, starting at `column`
+pub fn wrap(text: &str, width: usize, column: usize) -> String {
+    let mut wrapper = WordWrapper::new(width, column);
+    let mut out = wrapper.push(text);
+    out.push_str(&wrapper.finish());
+    out
+}
@@ -0,0 +1,244 @@
+//! Helpers shared by the unit tests: a default config, a scratch directory and a canned HTTP backend
+
+use crate::config::Config;
+use crate::llm::Backend;
+use crate::logger::LogBackend;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Config with the command line defaults, talking to `api_base_url`
+pub fn config(api_base_url: &str) -> Config {
+    Config {
+        bind: "127.0.0.1".parse().unwrap(),
+        port: 0,
+        api_base_url: api_base_url.to_string(),
+        fallback_endpoints: Vec::new(),
+        backend: Backend::OpenAiCompatible,
+        azure_deployment: None,
+        azure_api_version: "2024-10-21".to_string(),
+        model: "default".to_string(),
+        temperature: None,
+        top_p: None,
+        max_tokens: None,
+        seed: None,
+        api_key: None,
+        extra_headers: Default::default(),
+        proxy: None,
+        ca_certs: Vec::new(),
+        insecure_skip_verify: false,
+        system_prompt: "You are a helpful AI assistant.".to_string(),
+        input_template: None,
+        prompt_string: "You: ".to_string(),
+        translations: Default::default(),
+        max_request_bytes: None,
+        request_timeout_secs: 5,
+        max_retries: 0,
+        retry_on_context_error: true,
+        allow_model_ansi: false,
+        rewrite_rules: Vec::new(),
+        personas: Default::default(),
+        enable_tools: false,
+        stream: false,
+        logs_dir: PathBuf::from("logs"),
+        log_backend: LogBackend::Files,
+        host_key_paths: Vec::new(),
+        on_connect_cmd: None,
+        on_disconnect_cmd: None,
+        admin_keys: Vec::new(),
+        kiosk: false,
+        max_conns_per_ip: 0,
+        max_concurrent_llm: 0,
+        cache_size: 0,
+        max_sessions: 0,
+        idle_timeout_secs: 0,
+        reattach_grace_secs: 0,
+        daily_message_limit: 0,
+        authorized_keys: None,
+        require_key: false,
+        trust_proxy: false,
+        summarize_after: 40,
+        max_history_messages: 40,
+        max_loaded_history: 20,
+        max_log_bytes: 0,
+        log_retention_days: 0,
+        context_size: 8192,
+        banner: None,
+        color: false,
+        keepalive_secs: 0,
+        auth_rejection_ms: 0,
+        max_auth_attempts: 6,
+    }
+}
+
+/// Directory under the system temp dir, removed again when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("sshllm-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A whole HTTP response with a JSON body
+pub fn json_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// An OpenAI chat completion answering `content`
+pub fn openai_answer(content: &str) -> String {
+    let body = serde_json::json!({ "choices": [{ "message": { "role": "assistant", "content": content } }] });
+    json_response("200 OK", &body.to_string())
+}
+
+/// HTTP server answering each request with the next canned response; the last one repeats
+pub struct MockBackend {
+    pub url: String,
+    requests: Arc<AtomicUsize>,
+}
+
+impl MockBackend {
+    pub async fn start(responses: Vec<String>) -> Self {
+        Self::start_delayed(Duration::ZERO, responses).await
+    }
+
+    /// Like `start`, but each response is held back for `delay` after its request arrives
+    pub async fn start_delayed(delay: Duration, responses: Vec<String>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let response = responses[n.min(responses.len() - 1)].clone();
+                tokio::spawn(async move {
+                    read_request(&mut stream).await;
+                    tokio::time::sleep(delay).await;
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// Requests received so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+/// HTTP server that reads requests and never answers them, noting when the client hangs up
+pub struct SilentBackend {
+    pub url: String,
+    requests: Arc<AtomicUsize>,
+    hangups: Arc<AtomicUsize>,
+}
+
+impl SilentBackend {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let hangups = Arc::new(AtomicUsize::new(0));
+        let (received, closed) = (requests.clone(), hangups.clone());
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (received, closed) = (received.clone(), closed.clone());
+                tokio::spawn(async move {
+                    read_request(&mut stream).await;
+                    received.fetch_add(1, Ordering::SeqCst);
+                    // Nothing more is expected, so a read returns only when the client is gone
+                    let _ = stream.read(&mut [0u8; 1]).await;
+                    closed.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        Self { url, requests, hangups }
+    }
+
+    /// Requests received so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    /// Requests whose client closed the connection while waiting for an answer
+    pub fn hangups(&self) -> usize {
+        self.hangups.load(Ordering::SeqCst)
+    }
+}
+
+/// HTTP server answering one request with `parts` written separately, like a streamed
+/// response arriving in chunks; the body ends when the connection closes
+pub async fn stream_once(parts: Vec<&'static str>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            return;
+        };
+        read_request(&mut stream).await;
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n").await;
+        for part in parts {
+            let _ = stream.write_all(part.as_bytes()).await;
+            let _ = stream.flush().await;
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let _ = stream.shutdown().await;
+    });
+
+    url
+}
+
+/// Read a request's headers and its `Content-Length` body
+async fn read_request(stream: &mut tokio::net::TcpStream) {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let Ok(n) = stream.read(&mut buf).await else {
+            return;
+        };
+        if n == 0 {
+            return;
+        }
+        data.extend_from_slice(&buf[..n]);
+        let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+            continue;
+        };
+        let headers = String::from_utf8_lossy(&data[..end]).to_lowercase();
+        let length: usize = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0);
+        if data.len() >= end + 4 + length {
+            return;
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use chrono::{Local, Utc};
+use serde_json::{json, Value};
+
+/// A local function the model can call
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    /// JSON Schema for the arguments object
+    fn parameters(&self) -> Value;
+    fn call(&self, arguments: &Value) -> Result<String, String>;
+}
+
+/// Tools exposed to the model via the `tools` request field
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self { tools: Vec::new() }
+    }
+
+    /// Registry with the built-in `current_time` and `calculator` tools
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(TimeTool));
+        registry.register(Box::new(CalculatorTool));
+        registry
+    }
+
+    pub fn register(&mut self, tool: Box<dyn Tool>) {
+        self.tools.push(tool);
+    }
+
+    /// Tool definitions in OpenAI `tools` format
+    pub fn definitions(&self) -> Vec<Value> {
+        self.tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name(),
+                        "description": tool.description(),
+                        "parameters": tool.parameters(),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Run a tool call, returning the text handed back to the model
+    pub fn call(&self, name: &str, arguments: &str) -> String {
+        let Some(tool) = self.tools.iter().find(|t| t.name() == name) else {
+            return format!("Error: unknown tool '{}'", name);
+        };
+
+        let arguments: Value = if arguments.trim().is_empty() {
+            json!({})
+        } else {
+            match serde_json::from_str(arguments) {
+                Ok(args) => args,
+                Err(e) => return format!("Error: invalid arguments: {}", e),
+            }
+        };
+
+        tool.call(&arguments).unwrap_or_else(|e| format!("Error: {}", e))
+    }
+}
+
+impl Default for ToolRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reports the server's current date and time
+struct TimeTool;
+
+impl Tool for TimeTool {
+    fn name(&self) -> &str {
+        "current_time"
+    }
+
+    fn description(&self) -> &str {
+        "Get the current date and time"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn call(&self, _arguments: &Value) -> Result<String, String> {
+        Ok(format!(
+            "Local: {}\nUTC: {}",
+            Local::now().format("%A, %Y-%m-%d %H:%M:%S %:z"),
+            Utc::now().to_rfc3339()
+        ))
+    }
+}
+
+/// Longest expression the calculator accepts, in bytes
+const MAX_EXPRESSION_LEN: usize = 1024;
+
+/// Deepest nesting of parentheses, `-` and `^` the calculator parses; the input comes from
+/// the model, and unbounded recursion would overflow the stack and abort the server
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+/// Evaluates arithmetic expressions
+struct CalculatorTool;
+
+impl Tool for CalculatorTool {
+    fn name(&self) -> &str {
+        "calculator"
+    }
+
+    fn description(&self) -> &str {
+        "Evaluate an arithmetic expression with + - * / % ^ and parentheses"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": { "type": "string", "description": "Expression to evaluate, e.g. (2 + 3) * 4" }
+            },
+            "required": ["expression"]
+        })
+    }
+
+    fn call(&self, arguments: &Value) -> Result<String, String> {
+        let expression = arguments
+            .get("expression")
+            .and_then(Value::as_str)
+            .ok_or("missing 'expression' argument")?;
+        if expression.len() > MAX_EXPRESSION_LEN {
+            return Err(format!("expression longer than {} bytes", MAX_EXPRESSION_LEN));
+        }
+
+        let mut parser = ExprParser { chars: expression.chars().filter(|c| !c.is_whitespace()).collect(), pos: 0, depth: 0 };
+        let value = parser.expr()?;
+        if parser.pos < parser.chars.len() {
+            return Err(format!("unexpected '{}'", parser.chars[parser.pos]));
+        }
+        if !value.is_finite() {
+            return Err("result is not a finite number".to_string());
+        }
+        Ok(value.to_string())
+    }
+}
+
+/// Recursive-descent parser for the calculator tool
+struct ExprParser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Nesting level of the rule being parsed
+    depth: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    /// Parse a nested rule, one level deeper, up to `MAX_EXPRESSION_DEPTH`
+    fn nested(&mut self, rule: fn(&mut Self) -> Result<f64, String>) -> Result<f64, String> {
+        if self.depth >= MAX_EXPRESSION_DEPTH {
+            return Err(format!("expression nested more than {} levels deep", MAX_EXPRESSION_DEPTH));
+        }
+        self.depth += 1;
+        let value = rule(self);
+        self.depth -= 1;
+        value
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    // term := power (('*' | '/' | '%') power)*
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.power()?;
+            value = match op {
+                '*' => value * rhs,
+                '/' if rhs == 0.0 => return Err("division by zero".to_string()),
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    // power := unary ('^' power)?
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.unary()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            let exponent = self.nested(Self::power)?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    // unary := '-' unary | atom
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            return Ok(-self.nested(Self::unary)?);
+        }
+        self.atom()
+    }
+
+    // atom := number | '(' expr ')'
+    fn atom(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = self.nested(Self::expr)?;
+                if self.peek() != Some(')') {
+                    return Err("missing ')'".to_string());
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                number.parse().map_err(|_| format!("invalid number '{}'", number))
+            }
+            Some(c) => Err(format!("unexpected '{}'", c)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calc(expression: &str) -> Result<String, String> {
+        CalculatorTool.call(&json!({ "expression": expression }))
+    }
+
+    #[test]
+    fn operators_follow_precedence() {
+        assert_eq!(calc("2 + 3 * 4").unwrap(), "14");
+        assert_eq!(calc("(2 + 3) * 4").unwrap(), "20");
+        assert_eq!(calc("10 - 4 - 3").unwrap(), "3");
+        assert_eq!(calc("7 % 4 * 2").unwrap(), "6");
+        assert_eq!(calc("2 * 3 ^ 2").unwrap(), "18");
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        assert_eq!(calc("2 ^ 3 ^ 2").unwrap(), "512");
+        assert_eq!(calc("(2 ^ 3) ^ 2").unwrap(), "64");
+    }
+
+    #[test]
+    fn bad_expressions_are_errors() {
+        assert_eq!(calc("1 / 0").unwrap_err(), "division by zero");
+        assert_eq!(calc("1 / (2 - 2)").unwrap_err(), "division by zero");
+        assert_eq!(calc("(1 + 2").unwrap_err(), "missing ')'");
+        assert_eq!(calc("1 + 2)").unwrap_err(), "unexpected ')'");
+        assert_eq!(calc("1..2").unwrap_err(), "invalid number '1..2'");
+        assert_eq!(calc("10 ^ 400").unwrap_err(), "result is not a finite number");
+        assert!(CalculatorTool.call(&json!({})).is_err());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let at_limit = format!("{}1{}", "(".repeat(MAX_EXPRESSION_DEPTH), ")".repeat(MAX_EXPRESSION_DEPTH));
+        assert_eq!(calc(&at_limit).unwrap(), "1");
+
+        let too_deep = format!("{}1{}", "(".repeat(MAX_EXPRESSION_DEPTH + 1), ")".repeat(MAX_EXPRESSION_DEPTH + 1));
+        assert!(calc(&too_deep).unwrap_err().contains("levels deep"));
+        assert!(calc(&format!("{}1", "-".repeat(500))).unwrap_err().contains("levels deep"));
+        assert!(calc(&format!("{}2", "2^".repeat(300))).unwrap_err().contains("levels deep"));
+
+        // Far too deep to parse recursively, refused before parsing starts
+        let huge = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert!(calc(&huge).unwrap_err().contains("longer than"));
+    }
+}
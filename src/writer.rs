@@ -0,0 +1,88 @@
+use crate::terminal::strip_colors;
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use tokio::sync::mpsc;
+
+enum WriteOp {
+    Data(Vec<u8>),
+    /// Bytes sent just before the channel closes, e.g. to reset terminal modes
+    OnClose(Vec<u8>),
+    /// Report the exit status, then send EOF and close the channel
+    Close(u32),
+}
+
+/// Ordered output queue for a single channel.
+///
+/// Every write, whether from the SSH handler or a spawned response task, goes
+/// through one writer task, so output reaches the client in submission order.
+#[derive(Clone)]
+pub struct ChannelWriter {
+    tx: mpsc::UnboundedSender<WriteOp>,
+    /// Whether color/style sequences are passed on (off for `--no-color`)
+    color: bool,
+}
+
+impl ChannelWriter {
+    pub fn spawn(handle: Handle, channel: ChannelId, color: bool) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut on_close = Vec::new();
+            while let Some(op) = rx.recv().await {
+                match op {
+                    WriteOp::Data(bytes) => {
+                        if handle.data(channel, CryptoVec::from(bytes)).await.is_err() {
+                            break;
+                        }
+                    }
+                    WriteOp::OnClose(bytes) => on_close = bytes,
+                    WriteOp::Close(status) => {
+                        if !on_close.is_empty() {
+                            let _ = handle.data(channel, CryptoVec::from(on_close)).await;
+                        }
+                        // Clients treat a close without these as an abnormal end
+                        let _ = handle.exit_status_request(channel, status).await;
+                        let _ = handle.eof(channel).await;
+                        let _ = handle.close(channel).await;
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self { tx, color }
+    }
+
+    /// Queue bytes for the channel
+    pub fn write(&self, data: impl AsRef<[u8]>) {
+        let data = data.as_ref();
+        if data.is_empty() {
+            return;
+        }
+        let bytes = match std::str::from_utf8(data) {
+            Ok(text) if !self.color => strip_colors(text).into_bytes(),
+            _ => data.to_vec(),
+        };
+        let _ = self.tx.send(WriteOp::Data(bytes));
+    }
+
+    /// Whether the writer task has stopped (channel closed or session gone)
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Send `data` right before the channel is closed, whichever path closes it
+    pub fn on_close(&self, data: impl Into<Vec<u8>>) {
+        let _ = self.tx.send(WriteOp::OnClose(data.into()));
+    }
+
+    /// Close the channel with exit status 0 once everything queued before it has been sent
+    pub fn close(&self) {
+        self.exit(0);
+    }
+
+    /// Like `close`, reporting `status` as the exit status
+    pub fn exit(&self, status: u32) {
+        let _ = self.tx.send(WriteOp::Close(status));
+    }
+}